@@ -1,8 +1,16 @@
 use clap::{Parser, Subcommand};
 use pando_core::{
+    exec::{ExecEvent, ExecInput, ExecRequest},
     grpc_remote::{GetAvailableDevicesRequest, Schedule},
+    logs::{LogStream, LogsRequest},
     schedule::Spec,
 };
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncReadExt;
+use tokio::signal::unix::{signal, SignalKind};
+
+mod terminal;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -51,6 +59,67 @@ enum DevicesSubCommand {
         #[clap(long)]
         registration_token: String,
     },
+
+    /// Ask a live device for the schedule it last applied
+    #[clap(name = "schedule")]
+    Schedule {
+        device_id: String,
+        #[clap(long)]
+        nats_url: String,
+        #[clap(long, default_value_t = 5)]
+        timeout_secs: u64,
+    },
+
+    /// Show the logs of a service running on a live device
+    #[clap(name = "logs")]
+    Logs {
+        device_id: String,
+        /// Service name or task id
+        service: String,
+        #[clap(long)]
+        nats_url: String,
+        /// Keep streaming new output until interrupted
+        #[clap(long, short)]
+        follow: bool,
+        /// Number of lines to show from the end of the logs
+        #[clap(long)]
+        tail: Option<u64>,
+        /// Only show logs newer than this, e.g. `10m` or `1h30m`
+        #[clap(long)]
+        since: Option<String>,
+        #[clap(long, default_value_t = 5)]
+        timeout_secs: u64,
+    },
+
+    /// Run a command in a service running on a live device
+    #[clap(name = "exec")]
+    Exec {
+        device_id: String,
+        /// Service name or task id
+        service: String,
+        #[clap(long)]
+        nats_url: String,
+        /// Keep stdin open and forward it to the command
+        #[clap(long, short)]
+        interactive: bool,
+        /// Allocate a pseudo-terminal
+        #[clap(long, short)]
+        tty: bool,
+        #[clap(long, default_value_t = 5)]
+        timeout_secs: u64,
+        #[clap(last = true, required = true)]
+        cmd: Vec<String>,
+    },
+
+    /// Ask a live device for a fresh stats snapshot
+    #[clap(name = "stats")]
+    Stats {
+        device_id: String,
+        #[clap(long)]
+        nats_url: String,
+        #[clap(long, default_value_t = 5)]
+        timeout_secs: u64,
+    },
 }
 
 #[derive(Debug, Subcommand, Clone)]
@@ -59,6 +128,94 @@ enum AppSubCommand {
     Devices(DevicesCommand),
 }
 
+/// Runs a command on a device, relaying this terminal to it. Returns the command's exit code.
+async fn exec(
+    nats_url: String,
+    device_id: &str,
+    request: ExecRequest,
+    timeout: Duration,
+) -> anyhow::Result<i32> {
+    let nats_client = pando_core::nats::Client::new(nats_url);
+    let mut session = nats_client.exec(device_id, &request, timeout).await?;
+
+    // Like `docker exec`, keystrokes only go raw when they are forwarded, so Ctrl-C still stops
+    // the CLI otherwise
+    let raw_mode = if request.tty && request.interactive {
+        Some(terminal::RawMode::enable()?)
+    } else {
+        None
+    };
+
+    if request.interactive {
+        let input = session.input();
+        tokio::spawn(async move {
+            let mut stdin = tokio::io::stdin();
+            let mut buf = vec![0; 4096];
+            loop {
+                let message = match stdin.read(&mut buf).await {
+                    Ok(0) | Err(_) => ExecInput::CloseStdin,
+                    Ok(n) => ExecInput::Stdin {
+                        data: buf[..n].to_vec(),
+                    },
+                };
+                let closed = message == ExecInput::CloseStdin;
+                if input.send(&message).await.is_err() || closed {
+                    break;
+                }
+            }
+        });
+    }
+
+    if request.tty {
+        let input = session.input();
+        let mut window_changes = signal(SignalKind::window_change())?;
+        tokio::spawn(async move {
+            while window_changes.recv().await.is_some() {
+                if let Some(size) = terminal::size() {
+                    if input.send(&ExecInput::Resize(size)).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    let mut code = 1;
+    while let Some(event) = session.next_event().await? {
+        match event {
+            ExecEvent::Started => {}
+            ExecEvent::Output {
+                stream: LogStream::Stdout,
+                data,
+            } => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&data)?;
+                stdout.flush()?;
+            }
+            ExecEvent::Output {
+                stream: LogStream::Stderr,
+                data,
+            } => {
+                let mut stderr = std::io::stderr().lock();
+                stderr.write_all(&data)?;
+                stderr.flush()?;
+            }
+            ExecEvent::Exit {
+                code: exit_code,
+                error,
+            } => {
+                if let Some(error) = error {
+                    eprintln!("{}", error);
+                }
+                code = exit_code.unwrap_or(1) as i32;
+            }
+        }
+    }
+
+    drop(raw_mode);
+    Ok(code)
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<(), anyhow::Error> {
     env_logger::init();
@@ -69,7 +226,7 @@ async fn main() -> anyhow::Result<(), anyhow::Error> {
             match db_cmd.schedule_subcommand {
                 ScheduleSubcommand::Emit {
                     schedule_path,
-                    device_id: _,
+                    device_id,
                     remote_service_endpoint,
                 } => {
                     let spec = Spec::read_from(&schedule_path)?;
@@ -83,6 +240,8 @@ async fn main() -> anyhow::Result<(), anyhow::Error> {
                     grpc_client
                         .publish_schedule(pando_core::grpc_remote::PublishScheduleRequest {
                             schedule: Some(schedule),
+                            device_ids: vec![device_id],
+                            fleet_id: String::new(),
                         })
                         .await?;
                 }
@@ -133,6 +292,96 @@ async fn main() -> anyhow::Result<(), anyhow::Error> {
                         println!("Successfully claimed device with ID: {}", device_id);
                     }
                 }
+                DevicesSubCommand::Schedule {
+                    device_id,
+                    nats_url,
+                    timeout_secs,
+                } => {
+                    let nats_client = pando_core::nats::Client::new(nats_url);
+                    match nats_client
+                        .get_schedule(&device_id, Duration::from_secs(timeout_secs))
+                        .await?
+                    {
+                        Some(schedule) => println!("{:#?}", schedule),
+                        None => println!("Device has not applied a schedule yet"),
+                    }
+                }
+                DevicesSubCommand::Logs {
+                    device_id,
+                    service,
+                    nats_url,
+                    follow,
+                    tail,
+                    since,
+                    timeout_secs,
+                } => {
+                    let since = match since {
+                        Some(since) => {
+                            let ago = pando_core::schedule::parse_duration(&since)
+                                .map_err(|e| anyhow::anyhow!(e))?;
+                            let since = SystemTime::now()
+                                .checked_sub(ago)
+                                .and_then(|since| since.duration_since(UNIX_EPOCH).ok())
+                                .ok_or_else(|| anyhow::anyhow!("--since reaches back too far"))?;
+                            Some(since.as_secs() as i64)
+                        }
+                        None => None,
+                    };
+
+                    let nats_client = pando_core::nats::Client::new(nats_url);
+                    nats_client
+                        .stream_logs(
+                            &device_id,
+                            &LogsRequest {
+                                task: service,
+                                follow,
+                                tail,
+                                since,
+                            },
+                            Duration::from_secs(timeout_secs),
+                            |stream, message| match stream {
+                                LogStream::Stdout => print!("{}", message),
+                                LogStream::Stderr => eprint!("{}", message),
+                            },
+                        )
+                        .await?;
+                }
+                DevicesSubCommand::Exec {
+                    device_id,
+                    service,
+                    nats_url,
+                    interactive,
+                    tty,
+                    timeout_secs,
+                    cmd,
+                } => {
+                    let code = exec(
+                        nats_url,
+                        &device_id,
+                        ExecRequest {
+                            task: service,
+                            cmd,
+                            interactive,
+                            // Raw mode needs a terminal to put into raw mode
+                            tty: tty && terminal::stdin_is_terminal(),
+                            size: if tty { terminal::size() } else { None },
+                        },
+                        Duration::from_secs(timeout_secs),
+                    )
+                    .await?;
+                    std::process::exit(code);
+                }
+                DevicesSubCommand::Stats {
+                    device_id,
+                    nats_url,
+                    timeout_secs,
+                } => {
+                    let nats_client = pando_core::nats::Client::new(nats_url);
+                    let stats = nats_client
+                        .get_stats(&device_id, Duration::from_secs(timeout_secs))
+                        .await?;
+                    println!("{:#?}", stats);
+                }
             }
         }
     }
@@ -0,0 +1,41 @@
+use std::io;
+
+use pando_core::exec::TerminalSize;
+use rustix::termios::{self, OptionalActions, Termios};
+
+/// Size of the terminal attached to stdout, if there is one.
+pub fn size() -> Option<TerminalSize> {
+    let winsize = termios::tcgetwinsize(io::stdout()).ok()?;
+    Some(TerminalSize {
+        rows: winsize.ws_row,
+        cols: winsize.ws_col,
+    })
+}
+
+pub fn stdin_is_terminal() -> bool {
+    termios::isatty(io::stdin())
+}
+
+/// Puts the terminal on stdin into raw mode, so keystrokes such as Ctrl-C reach the remote
+/// command instead of this process. The previous mode is restored when dropped.
+pub struct RawMode {
+    original: Termios,
+}
+
+impl RawMode {
+    pub fn enable() -> io::Result<Self> {
+        let original = termios::tcgetattr(io::stdin())?;
+
+        let mut raw = original.clone();
+        raw.make_raw();
+        termios::tcsetattr(io::stdin(), OptionalActions::Now, &raw)?;
+
+        Ok(RawMode { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(io::stdin(), OptionalActions::Now, &self.original);
+    }
+}
@@ -42,6 +42,11 @@ fn main() {
         .build_server(true)
         .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
         .type_attribute(".", "#[serde(rename_all = \"camelCase\")]")
+        // Schedules are stored as JSON on the server, so older bodies must still load after fields
+        // are added
+        .message_attribute(".", "#[serde(default)]")
+        // Debug is implemented by hand so the password never ends up in logs
+        .skip_debug(".remote.upd88.com.RegistryCredential")
         .compile_protos(
             &[
                 "protos/remote/upd88/com/types.proto",
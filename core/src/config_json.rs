@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::grpc_remote::RegistryCredential;
+
 // I want to allow choices in how identifiers are generated,
 // but I'm skeptical that doing this on the device is the best approach.
 // Being server-authoritative is a good way to ensure that identifiers are unique.
@@ -50,6 +52,10 @@ pub struct ConfigJson {
     #[serde(rename = "apiEndpoint")]
     pub api_endpoint: Option<String>,
 
+    /// Used for image pulls when the schedule doesn't bring its own credentials for a registry.
+    #[serde(rename = "registryCredentials")]
+    pub registry_credentials: Option<Vec<RegistryCredential>>,
+
     // #[serde(rename = "init")]
     // pub init: Option<ConfigJsonInit>
-}
\ No newline at end of file
+}
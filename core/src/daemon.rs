@@ -1,38 +1,190 @@
 use anyhow::Result;
 use async_nats::Subject;
-use bollard::container::{KillContainerOptions, StartContainerOptions};
-use bollard::secret::{PortBinding, PortMap, SystemVersionPlatform};
+use bollard::auth::DockerCredentials;
+use bollard::container::{
+    LogOutput, LogsOptions, RemoveContainerOptions, StartContainerOptions, StatsOptions,
+};
+use bollard::exec::{CreateExecOptions, ResizeExecOptions, StartExecOptions, StartExecResults};
+use bollard::network::{ConnectNetworkOptions, CreateNetworkOptions, ListNetworksOptions};
+use bollard::secret::{
+    DeviceMapping, EndpointSettings, HealthConfig, HealthStatusEnum, Ipam, IpamConfig, PortBinding,
+    PortMap, ResourcesUlimits, RestartPolicyNameEnum, SystemVersionPlatform,
+};
+use bollard::system::EventsOptions;
 use bollard::{container::ListContainersOptions, Docker, API_DEFAULT_VERSION};
 use bytes::Bytes;
 use futures::StreamExt;
 use prost::Message;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, Notify};
 use tokio::{task, time};
 use tracing::debug;
 use uuid::Uuid;
 
 use crate::config_json::ConfigJson;
+use crate::events::{ContainerEvent, ContainerEventKind};
+use crate::exec::{ExecEvent, ExecInput, ExecRequest, TerminalSize};
+use crate::grpc_remote::device_service_client::DeviceServiceClient;
 use crate::grpc_remote::{
-    CheckAnonymousDeviceRegistrationRequest, ContainerPortDefinition, RegistrationFailureStatus,
-    Schedule,
+    CheckAnonymousDeviceRegistrationRequest, Container, ContainerHealthcheck, ContainerNetwork,
+    ContainerState, GetScheduleRequest, Network, RegistrationFailureStatus, RegistryCredential,
+    ReportScheduleStateRequest, RestartPolicy, Schedule,
 };
-use crate::temp::{list_zones, Temperature};
-use crate::{config, registration};
+use crate::images::ImageLedger;
+use crate::logs::{LogEvent, LogStream, LogsRequest};
+use crate::registry::credentials_for_image;
+use crate::schedule_store::ScheduleStore;
+use crate::stats::{
+    ContainerResourceUsage, ContainerStats, ContainerStatsReport, SystemStats, STATS_VERSION,
+};
+use crate::{config, nats, registration};
 
 const DEFAULT_DOCKER_ENGINE_SOCKET: &str = "/run/balena-engine.sock";
+const DEFAULT_HOST_BOOT_PATH: &str = "/mnt/boot";
+const DEFAULT_IMAGE_KEEP_LAST: usize = 2;
+const DOCKER_REQUEST_TIMEOUT_SECS: u64 = 120;
+/// What the engine waits for before killing a container that sets no stop timeout of its own
+const DEFAULT_STOP_TIMEOUT_SECS: i64 = 10;
+const STATE_REPORT_INTERVAL_SECS: u64 = 60;
+const DEVICE_API_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_STATS_INTERVAL_SECS: u64 = 5;
+/// Device classes the `udev` host feature grants access to, so nodes for hotplugged devices can
+/// be created in the container. Specific nodes are passed in with `devices` instead.
+const UDEV_DEVICE_CGROUP_RULES: &[&str] = &[
+    // Input event devices
+    "c 13:* rwm",
+    // I2C adapters
+    "c 89:* rwm",
+    // USB modems, /dev/ttyACM*
+    "c 166:* rwm",
+    // USB serial adapters, /dev/ttyUSB*
+    "c 188:* rwm",
+    // Raw USB devices, /dev/bus/usb
+    "c 189:* rwm",
+];
+/// How long the engine gets to apply a container's own restart policy before we step in
+const ENFORCE_DELAY_SECS: u64 = 2;
+const EVENTS_RETRY_SECS: u64 = 5;
+/// How often a session checks that its requester is still listening
+const SESSION_PING_INTERVAL_SECS: u64 = 15;
+const SESSION_PING_TIMEOUT_SECS: u64 = 5;
+
+/// Names of the host features a task uses, as they appear in its labels.
+fn host_features_for(task: &Container) -> Vec<&'static str> {
+    [
+        (task.bind_docker_socket, "daemon-socket"),
+        (task.bind_boot, "boot-partition"),
+        (task.bind_journal_logs, "journal-logs"),
+        (task.bind_dbus, "dbus"),
+        (task.bind_udev, "udev"),
+        (task.bind_kernel_modules, "kernel-modules"),
+    ]
+    .into_iter()
+    .filter_map(|(enabled, feature)| enabled.then_some(feature))
+    .collect()
+}
+
+/// Whether the engine doesn't know the object, e.g. a container removed since it was listed.
+fn is_not_found(error: &bollard::errors::Error) -> bool {
+    matches!(
+        error,
+        bollard::errors::Error::DockerResponseServerError {
+            status_code: 404,
+            ..
+        }
+    )
+}
+
+fn restart_policy_for(task: &Container) -> bollard::models::RestartPolicy {
+    let name = match task.restart_policy() {
+        RestartPolicy::No => RestartPolicyNameEnum::NO,
+        RestartPolicy::Always => RestartPolicyNameEnum::ALWAYS,
+        RestartPolicy::OnFailure => RestartPolicyNameEnum::ON_FAILURE,
+        // Survives engine restarts, but stays down once we deliberately stop it
+        RestartPolicy::UnlessStopped | RestartPolicy::Unspecified => {
+            RestartPolicyNameEnum::UNLESS_STOPPED
+        }
+    };
+
+    bollard::models::RestartPolicy {
+        maximum_retry_count: match name {
+            RestartPolicyNameEnum::ON_FAILURE => Some(task.restart_max_retries.into()),
+            _ => None,
+        },
+        name: Some(name),
+    }
+}
+
+fn health_config_for(healthcheck: &ContainerHealthcheck) -> HealthConfig {
+    // The engine wants nanoseconds, and treats zero as "inherit from the image"
+    let nanos = |millis: i64| {
+        if millis > 0 {
+            Some(millis * 1_000_000)
+        } else {
+            None
+        }
+    };
+
+    HealthConfig {
+        test: if healthcheck.test.is_empty() {
+            None
+        } else {
+            Some(healthcheck.test.clone())
+        },
+        interval: nanos(healthcheck.interval_ms),
+        timeout: nanos(healthcheck.timeout_ms),
+        retries: if healthcheck.retries > 0 {
+            Some(healthcheck.retries.into())
+        } else {
+            None
+        },
+        start_period: nanos(healthcheck.start_period_ms),
+        start_interval: None,
+    }
+}
+
+/// The engine's name for a network a container attaches to.
+fn engine_network_name(name: &str) -> &str {
+    // Compose calls the network containers land on without further configuration "default"
+    if name == "default" {
+        "bridge"
+    } else {
+        name
+    }
+}
+
+fn endpoint_settings_for(network: &ContainerNetwork) -> EndpointSettings {
+    EndpointSettings {
+        aliases: if network.aliases.is_empty() {
+            None
+        } else {
+            Some(network.aliases.clone())
+        },
+        ..Default::default()
+    }
+}
 
 #[derive(Debug)]
 struct Runner {
     docker: Docker,
     host_socket_path: String,
+    host_boot_path: String,
+    registry_credentials: Vec<RegistryCredential>,
+    /// Containers we are removing ourselves, so their events aren't mistaken for crashes.
+    /// An id stays until its destroy event has been seen.
+    retiring: Mutex<HashSet<String>>,
 }
 
 impl Runner {
-    async fn new(socket: &str) -> Result<Self, bollard::errors::Error> {
-        let docker = Docker::connect_with_unix(socket, 120, API_DEFAULT_VERSION)?;
+    async fn new(socket: &str, boot_path: &str) -> Result<Self, bollard::errors::Error> {
+        let docker =
+            Docker::connect_with_unix(socket, DOCKER_REQUEST_TIMEOUT_SECS, API_DEFAULT_VERSION)?;
         let version = docker.version().await?;
         println!(
             "Connected to docker engine {} {} {} {}",
@@ -49,6 +201,9 @@ impl Runner {
         Ok(Runner {
             docker,
             host_socket_path: socket.to_string(),
+            host_boot_path: boot_path.to_string(),
+            registry_credentials: Vec::new(),
+            retiring: Mutex::new(HashSet::new()),
         })
     }
 
@@ -70,12 +225,282 @@ impl Runner {
         self.docker.list_containers(Some(options)).await
     }
 
-    async fn kill_container(&self, container_id: &str) -> Result<(), bollard::errors::Error> {
+    /// Inspects every managed container and describes it the way we report it to the server.
+    async fn collect_container_states(
+        &self,
+    ) -> Result<Vec<ContainerState>, bollard::errors::Error> {
+        let containers = self
+            .list_containers_matching_label("io.uinta.pando.managed", "true")
+            .await?;
+
+        let mut states = Vec::new();
+        for container in containers {
+            let container_id = container.id.unwrap_or_default();
+            let labels = container.labels.unwrap_or_default();
+            let label = |key: &str| labels.get(key).cloned().unwrap_or_default();
+
+            // Containers come and go while a schedule is applied
+            let inspected = match self.docker.inspect_container(&container_id, None).await {
+                Err(e) if is_not_found(&e) => continue,
+                result => result?,
+            };
+            let state = inspected.state.unwrap_or_default();
+
+            states.push(ContainerState {
+                id: label("io.uinta.pando.task-id"),
+                name: label("io.uinta.pando.task-name"),
+                schedule_id: label("io.uinta.pando.schedule-id"),
+                status: state.status.map(|s| s.to_string()).unwrap_or_default(),
+                error: state.error.unwrap_or_default(),
+                health: match state.health.and_then(|health| health.status) {
+                    Some(HealthStatusEnum::EMPTY) | Some(HealthStatusEnum::NONE) | None => {
+                        "".to_string()
+                    }
+                    Some(status) => status.to_string(),
+                },
+            });
+        }
+
+        Ok(states)
+    }
+
+    /// Finds the managed container running a task, by task id or service name.
+    async fn find_managed_container(
+        &self,
+        task: &str,
+    ) -> Result<Option<String>, bollard::errors::Error> {
+        let containers = self
+            .list_containers_matching_label("io.uinta.pando.managed", "true")
+            .await?;
+
+        Ok(containers
+            .into_iter()
+            .find(|container| {
+                container.labels.as_ref().is_some_and(|labels| {
+                    labels.get("io.uinta.pando.task-id").map(String::as_str) == Some(task)
+                        || labels.get("io.uinta.pando.task-name").map(String::as_str) == Some(task)
+                })
+            })
+            .and_then(|container| container.id))
+    }
+
+    async fn resize_exec(&self, exec_id: &str, size: TerminalSize) {
+        let options = ResizeExecOptions {
+            height: size.rows,
+            width: size.cols,
+        };
+        if let Err(e) = self.docker.resize_exec(exec_id, options).await {
+            println!("Error resizing exec: {:?}", e);
+        }
+    }
+
+    /// Samples resource usage of every managed container. Running containers are sampled
+    /// concurrently since each sample takes a couple of seconds to compute CPU usage.
+    async fn collect_container_stats(
+        &self,
+    ) -> Result<BTreeMap<String, ContainerStats>, bollard::errors::Error> {
+        let containers = self
+            .list_containers_matching_label("io.uinta.pando.managed", "true")
+            .await?;
+
+        let samples = containers.into_iter().map(|container| async move {
+            let container_id = container.id.unwrap_or_default();
+            let labels = container.labels.unwrap_or_default();
+            let label = |key: &str| labels.get(key).cloned().unwrap_or_default();
+
+            // Containers come and go while a schedule is applied
+            let inspected = match self.docker.inspect_container(&container_id, None).await {
+                Err(e) if is_not_found(&e) => return Ok(None),
+                result => result?,
+            };
+            let state = inspected.state.unwrap_or_default();
+
+            let usage = if state.running.unwrap_or_default() {
+                let options = StatsOptions {
+                    stream: false,
+                    one_shot: false,
+                };
+                match self.docker.stats(&container_id, Some(options)).next().await {
+                    Some(Ok(stats)) => Some(ContainerResourceUsage::from_docker(&stats)),
+                    Some(Err(e)) => {
+                        println!("Error sampling stats for {}: {:?}", container_id, e);
+                        None
+                    }
+                    None => None,
+                }
+            } else {
+                None
+            };
+
+            let stats = ContainerStats {
+                name: label("io.uinta.pando.task-name"),
+                restart_count: inspected.restart_count.unwrap_or_default(),
+                last_exit_code: state.exit_code,
+                usage,
+                container_id,
+            };
+            Ok::<_, bollard::errors::Error>(Some((label("io.uinta.pando.task-id"), stats)))
+        });
+
+        let mut stats = BTreeMap::new();
+        for sample in futures::future::join_all(samples).await {
+            stats.extend(sample?);
+        }
+        Ok(stats)
+    }
+
+    /// Stops a container with the signal and grace period it was created with, then removes it
+    /// along with its anonymous volumes.
+    async fn retire_container(&self, container_id: &str) -> Result<(), bollard::errors::Error> {
+        self.retiring
+            .lock()
+            .unwrap()
+            .insert(container_id.to_string());
+
+        let result = self.stop_and_remove_container(container_id).await;
+        if result.is_err() {
+            // No destroy event is coming to clear it
+            self.retiring.lock().unwrap().remove(container_id);
+        }
+        result
+    }
+
+    async fn stop_and_remove_container(
+        &self,
+        container_id: &str,
+    ) -> Result<(), bollard::errors::Error> {
+        // The engine waits out the container's stop_grace_period before answering, which may be
+        // longer than the client's usual request timeout
+        let grace_period_secs = self
+            .docker
+            .inspect_container(container_id, None)
+            .await?
+            .config
+            .and_then(|config| config.stop_timeout)
+            .unwrap_or(DEFAULT_STOP_TIMEOUT_SECS)
+            .max(0) as u64;
+        let docker = self.docker.clone().with_timeout(Duration::from_secs(
+            grace_period_secs + DOCKER_REQUEST_TIMEOUT_SECS,
+        ));
+
+        match docker.stop_container(container_id, None).await {
+            // Already stopped
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 304, ..
+            }) => {}
+            result => result?,
+        }
+
+        self.docker
+            .remove_container(
+                container_id,
+                Some(RemoveContainerOptions {
+                    v: true,
+                    force: true,
+                    ..Default::default()
+                }),
+            )
+            .await
+    }
+
+    /// Whether an event is fallout from us retiring the container. Forgets the container once it
+    /// is destroyed.
+    fn is_retiring(&self, event: &ContainerEvent) -> bool {
+        let mut retiring = self.retiring.lock().unwrap();
+        match event.kind {
+            ContainerEventKind::Destroy => retiring.remove(&event.container_id),
+            _ => retiring.contains(&event.container_id),
+        }
+    }
+
+    /// Whether a container that died or was destroyed left the device short of what the schedule
+    /// wants: it is gone, or stopped with a restart policy that keeps it running and yet the
+    /// engine isn't restarting it, e.g. after a manual stop.
+    async fn needs_enforcement(&self, event: &ContainerEvent) -> bool {
+        if event.kind == ContainerEventKind::Destroy {
+            return true;
+        }
+
+        let inspected = match self
+            .docker
+            .inspect_container(&event.container_id, None)
+            .await
+        {
+            Ok(inspected) => inspected,
+            Err(e) if is_not_found(&e) => return true,
+            Err(e) => {
+                println!("Error inspecting container: {:?}", e);
+                return false;
+            }
+        };
+
+        let state = inspected.state.unwrap_or_default();
+        if state.running == Some(true) || state.restarting == Some(true) {
+            return false;
+        }
+
+        let policy = inspected
+            .host_config
+            .and_then(|host_config| host_config.restart_policy)
+            .and_then(|restart_policy| restart_policy.name);
+        matches!(
+            policy,
+            Some(RestartPolicyNameEnum::ALWAYS) | Some(RestartPolicyNameEnum::UNLESS_STOPPED)
+        )
+    }
+
+    async fn list_exited_containers(
+        &self,
+    ) -> Result<Vec<bollard::models::ContainerSummary>, bollard::errors::Error> {
+        let mut filters = HashMap::new();
+        filters.insert("label", vec!["io.uinta.pando.managed=true"]);
+        filters.insert("status", vec!["exited", "dead"]);
+
         self.docker
-            .kill_container(container_id, None::<KillContainerOptions<String>>)
+            .list_containers(Some(ListContainersOptions {
+                all: true,
+                filters,
+                ..Default::default()
+            }))
             .await
     }
 
+    /// Removes managed containers that exited or died, e.g. ones left over from before a reboot
+    /// or stopped by hand, so applying the schedule brings them back. Tasks of `schedule` whose
+    /// restart policy lets them stay down, such as finished one-shots, are left as they are.
+    async fn sweep_exited_containers(
+        &self,
+        schedule: Option<&Schedule>,
+    ) -> Result<(), bollard::errors::Error> {
+        for container in self.list_exited_containers().await? {
+            let labels = container.labels.unwrap_or_default();
+            let task = schedule.and_then(|schedule| {
+                schedule
+                    .containers
+                    .iter()
+                    .find(|task| labels.get("io.uinta.pando.task-id") == Some(&task.id))
+            });
+
+            // On failure the engine gives up once retries run out, which we leave be
+            if task.is_some_and(|task| {
+                matches!(
+                    task.restart_policy(),
+                    RestartPolicy::No | RestartPolicy::OnFailure
+                )
+            }) {
+                continue;
+            }
+
+            let container_id = container.id.unwrap_or_default();
+            println!("Sweeping exited container {}", container_id);
+            if let Err(e) = self.retire_container(&container_id).await {
+                println!("Error sweeping container: {:?}", e);
+            }
+        }
+
+        Ok(())
+    }
+
     async fn image_exists_locally(&self, image: &str) -> Result<bool, bollard::errors::Error> {
         self.docker
             .image_history(image)
@@ -84,13 +509,17 @@ impl Runner {
             .or_else(|_| Ok(false))
     }
 
-    async fn pull_image(&self, image: &str) -> Result<(), bollard::errors::Error> {
+    async fn pull_image(
+        &self,
+        image: &str,
+        credentials: Option<DockerCredentials>,
+    ) -> Result<(), bollard::errors::Error> {
         let options = bollard::image::CreateImageOptions {
             from_image: image,
             ..Default::default()
         };
 
-        let mut stream = self.docker.create_image(Some(options), None, None);
+        let mut stream = self.docker.create_image(Some(options), None, credentials);
         while let Some(result) = stream.next().await {
             match result {
                 Ok(_) => continue,
@@ -100,25 +529,220 @@ impl Runner {
         Ok(())
     }
 
-    async fn run_container(
+    async fn remove_image(
         &self,
         image: &str,
-        command: Option<Vec<String>>,
-        env: Vec<String>,
+    ) -> Result<Vec<bollard::models::ImageDeleteResponseItem>, bollard::errors::Error> {
+        self.docker.remove_image(image, None, None).await
+    }
+
+    async fn ensure_volume(&self, name: &str) -> Result<(), bollard::errors::Error> {
+        let mut labels = HashMap::new();
+        labels.insert("io.uinta.pando.managed", "true");
+
+        // The engine hands back the existing volume if one with this name is already present
+        self.docker
+            .create_volume(bollard::volume::CreateVolumeOptions {
+                name,
+                labels,
+                ..Default::default()
+            })
+            .await
+            .map(|_| ())
+    }
+
+    /// Removes managed networks the schedule no longer defines, or defines differently, then
+    /// creates the missing ones. Containers still attached keep a network from being removed, so
+    /// this runs after the containers using it are retired.
+    async fn sync_networks(&self, networks: &[Network]) -> Result<(), bollard::errors::Error> {
+        let mut filters = HashMap::new();
+        filters.insert("label", vec!["io.uinta.pando.managed=true"]);
+
+        let existing = self
+            .docker
+            .list_networks(Some(ListNetworksOptions { filters }))
+            .await?;
+
+        let mut current = HashSet::new();
+        for network in existing {
+            let name = network.name.unwrap_or_default();
+            let definition_hash = network
+                .labels
+                .as_ref()
+                .and_then(|labels| labels.get("io.uinta.pando.definition-hash"));
+
+            match networks.iter().find(|wanted| wanted.name == name) {
+                Some(wanted) if definition_hash == Some(&wanted.definition_hash()) => {
+                    current.insert(name);
+                }
+                _ => {
+                    println!("Removing network {}", name);
+                    if let Err(e) = self.docker.remove_network(&name).await {
+                        println!("Error removing network: {:?}", e);
+                    }
+                }
+            }
+        }
+
+        for network in networks {
+            if current.contains(&network.name) {
+                continue;
+            }
+
+            let definition_hash = network.definition_hash();
+            let mut labels = HashMap::new();
+            labels.insert("io.uinta.pando.managed", "true");
+            labels.insert("io.uinta.pando.definition-hash", definition_hash.as_str());
+
+            let non_empty = |s: &String| if s.is_empty() { None } else { Some(s.clone()) };
+            let options = CreateNetworkOptions {
+                name: network.name.as_str(),
+                check_duplicate: true,
+                driver: if network.driver.is_empty() {
+                    "bridge"
+                } else {
+                    network.driver.as_str()
+                },
+                internal: network.internal,
+                ipam: Ipam {
+                    config: Some(
+                        network
+                            .ipam_pools
+                            .iter()
+                            .map(|pool| IpamConfig {
+                                subnet: non_empty(&pool.subnet),
+                                gateway: non_empty(&pool.gateway),
+                                ip_range: non_empty(&pool.ip_range),
+                                ..Default::default()
+                            })
+                            .collect(),
+                    ),
+                    ..Default::default()
+                },
+                options: network
+                    .driver_opts
+                    .iter()
+                    .map(|opt| (opt.key.as_str(), opt.value.as_str()))
+                    .collect(),
+                labels,
+                ..Default::default()
+            };
+
+            println!("Creating network {}", network.name);
+            if let Err(e) = self.docker.create_network(options).await {
+                println!("Error creating network: {:?}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn run_container(
+        &self,
+        task: &Container,
         labels: HashMap<String, String>,
-        bind_docker_socket: bool,
-        network_mode_host: bool,
-        ports: Vec<ContainerPortDefinition>,
-    ) -> Result<String, bollard::errors::Error> {
+    ) -> Result<String, anyhow::Error> {
+        // Zero means unset in the proto, and leaving it out keeps the engine default
+        let set = |value: i64| if value != 0 { Some(value) } else { None };
+
         let mut binds = Vec::new();
-        if bind_docker_socket {
+        if task.bind_docker_socket {
             binds.push(format!("{}:/var/run/docker.sock", self.host_socket_path));
         }
+        if task.bind_boot {
+            binds.push(format!("{}:/boot", self.host_boot_path));
+        }
+        if task.bind_journal_logs {
+            binds.push("/var/log/journal:/var/log/journal:ro".to_string());
+            binds.push("/run/log/journal:/run/log/journal:ro".to_string());
+            // journalctl needs it to find the journal files of this boot
+            binds.push("/etc/machine-id:/etc/machine-id:ro".to_string());
+        }
+        if task.bind_dbus {
+            // Kept out of the container's own /run/dbus so it can still run a bus of its own
+            binds.push("/run/dbus:/host/run/dbus".to_string());
+        }
+        let mut device_cgroup_rules = task.device_cgroup_rules.clone();
+        if task.bind_udev {
+            binds.push("/run/udev:/run/udev:ro".to_string());
+            device_cgroup_rules.extend(UDEV_DEVICE_CGROUP_RULES.iter().map(|r| r.to_string()));
+        }
+        if task.bind_kernel_modules {
+            binds.push("/lib/modules:/lib/modules:ro".to_string());
+        }
+
+        let mut anonymous_volumes = HashMap::new();
+        for volume in &task.volumes {
+            if volume.source.is_empty() {
+                anonymous_volumes.insert(volume.container_path.as_str(), HashMap::new());
+                continue;
+            }
+
+            // Anything that isn't an absolute host path is a named volume we own
+            if !volume.source.starts_with('/') {
+                self.ensure_volume(&volume.source).await?;
+            }
+            let mode = if volume.read_only { ":ro" } else { "" };
+            binds.push(format!(
+                "{}:{}{}",
+                volume.source, volume.container_path, mode
+            ));
+        }
+
+        let network_mode = if let Some(target) = task.network_container() {
+            // Containers have no fixed names, so point at whichever one runs the target now
+            let running = match self.find_managed_container(target).await? {
+                Some(container_id) => {
+                    let inspected = self.docker.inspect_container(&container_id, None).await?;
+                    let running = inspected.state.and_then(|state| state.running);
+                    running.unwrap_or_default().then_some(container_id)
+                }
+                None => None,
+            };
+            let Some(container_id) = running else {
+                anyhow::bail!(
+                    "Container '{}' to share the network of isn't running",
+                    target
+                );
+            };
+            Some(format!("container:{}", container_id))
+        } else if let Some(network) = task.networks.first() {
+            Some(engine_network_name(&network.name).to_string())
+        } else {
+            match task.network_mode.as_str() {
+                "host" | "none" => Some(task.network_mode.clone()),
+                _ => None,
+            }
+        };
+
+        let mut env: Vec<String> = task
+            .environment
+            .iter()
+            .map(|e| format!("{}={}", e.key, e.value))
+            .collect();
+        if task.bind_dbus
+            && !task
+                .environment
+                .iter()
+                .any(|e| e.key == "DBUS_SYSTEM_BUS_ADDRESS")
+        {
+            env.push(
+                "DBUS_SYSTEM_BUS_ADDRESS=unix:path=/host/run/dbus/system_bus_socket".to_string(),
+            );
+        }
 
         // Convert String vectors to string slice vectors
-        let cmd: Option<Vec<&str>> = command
-            .as_ref()
-            .map(|c| c.iter().map(|s| s.as_str()).collect());
+        let cmd: Option<Vec<&str>> = if task.command.is_empty() {
+            None
+        } else {
+            Some(task.command.iter().map(|s| s.as_str()).collect())
+        };
+        let entrypoint_command = task.entrypoint_command();
+        let entrypoint: Option<Vec<&str>> = if entrypoint_command.is_empty() {
+            None
+        } else {
+            Some(entrypoint_command.iter().map(|s| s.as_str()).collect())
+        };
         let env_refs: Vec<&str> = env.iter().map(|s| s.as_str()).collect();
         let labels_refs: HashMap<&str, &str> = labels
             .iter()
@@ -126,13 +750,31 @@ impl Runner {
             .collect();
 
         let config = bollard::container::Config {
-            image: Some(image),
+            image: Some(task.container_image.as_str()),
             cmd,
+            entrypoint,
+            healthcheck: task.healthcheck.as_ref().map(health_config_for),
+            stop_signal: if task.stop_signal.is_empty() {
+                None
+            } else {
+                Some(task.stop_signal.as_str())
+            },
+            stop_timeout: if task.stop_grace_period_ms > 0 {
+                // The engine only takes whole seconds, so round up rather than cut it short
+                Some((task.stop_grace_period_ms + 999) / 1000)
+            } else {
+                None
+            },
             env: Some(env_refs),
             labels: Some(labels_refs),
+            volumes: if anonymous_volumes.is_empty() {
+                None
+            } else {
+                Some(anonymous_volumes)
+            },
             host_config: Some(bollard::models::HostConfig {
                 port_bindings: Some(
-                    ports
+                    task.ports
                         .iter()
                         .map(|port| {
                             let key = format!("{}/{}", port.container_port, port.protocol);
@@ -145,13 +787,56 @@ impl Runner {
                         .collect::<PortMap>(),
                 ),
                 binds: Some(binds),
-                network_mode: if network_mode_host {
-                    Some("host".to_string())
-                } else {
-                    None
-                },
+                privileged: Some(task.privileged),
+                restart_policy: Some(restart_policy_for(task)),
+                cap_add: Some(task.cap_add.clone()),
+                cap_drop: Some(task.cap_drop.clone()),
+                sysctls: Some(
+                    task.sysctls
+                        .iter()
+                        .map(|sysctl| (sysctl.key.clone(), sysctl.value.clone()))
+                        .collect(),
+                ),
+                ulimits: Some(
+                    task.ulimits
+                        .iter()
+                        .map(|ulimit| ResourcesUlimits {
+                            name: Some(ulimit.name.clone()),
+                            soft: Some(ulimit.soft),
+                            hard: Some(ulimit.hard),
+                        })
+                        .collect(),
+                ),
+                network_mode,
+                memory: set(task.mem_limit_bytes),
+                memory_swap: set(task.memswap_limit_bytes),
+                nano_cpus: set(task.nano_cpus),
+                cpu_shares: set(task.cpu_shares),
+                pids_limit: set(task.pids_limit),
+                oom_score_adj: set(task.oom_score_adj as i64),
+                oom_kill_disable: task.oom_kill_disable.then_some(true),
+                devices: Some(
+                    task.devices
+                        .iter()
+                        .map(|device| DeviceMapping {
+                            path_on_host: Some(device.host_path.clone()),
+                            path_in_container: Some(device.container_path.clone()),
+                            cgroup_permissions: Some(device.cgroup_permissions.clone()),
+                        })
+                        .collect(),
+                ),
+                device_cgroup_rules: Some(device_cgroup_rules),
                 ..Default::default()
             }),
+            // The engine only takes one network at creation, the rest are connected below
+            networking_config: task.networks.first().map(|network| {
+                bollard::container::NetworkingConfig {
+                    endpoints_config: HashMap::from([(
+                        engine_network_name(&network.name),
+                        endpoint_settings_for(network),
+                    )]),
+                }
+            }),
             ..Default::default()
         };
 
@@ -163,18 +848,54 @@ impl Runner {
             )
             .await?;
 
-        self.docker
-            .start_container(&container.id, None::<StartContainerOptions<String>>)
-            .await?;
+        // A container left behind in `created` would pass for the task on the next apply
+        if let Err(e) = self.connect_and_start(task, &container.id).await {
+            let options = RemoveContainerOptions {
+                v: true,
+                force: true,
+                ..Default::default()
+            };
+            if let Err(e) = self
+                .docker
+                .remove_container(&container.id, Some(options))
+                .await
+            {
+                println!("Error removing container that failed to start: {:?}", e);
+            }
+            return Err(e.into());
+        }
 
         Ok(container.id)
     }
+
+    async fn connect_and_start(
+        &self,
+        task: &Container,
+        container_id: &str,
+    ) -> Result<(), bollard::errors::Error> {
+        for network in task.networks.iter().skip(1) {
+            let options = ConnectNetworkOptions {
+                container: container_id,
+                endpoint_config: endpoint_settings_for(network),
+            };
+            self.docker
+                .connect_network(engine_network_name(&network.name), options)
+                .await?;
+        }
+
+        self.docker
+            .start_container(container_id, None::<StartContainerOptions<String>>)
+            .await
+    }
 }
 
 async fn apply_schedule(
     runner: &Runner,
     schedule: &Schedule,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    // Refuse a schedule we can't order before touching anything that is running
+    let start_order = schedule.start_order()?;
+
     // List existing containers
     let existing_containers = runner
         .list_containers_matching_label("io.uinta.pando.managed", "true")
@@ -182,30 +903,66 @@ async fn apply_schedule(
 
     // Track currently running containers
     let mut currently_running = HashMap::new();
+    let mut retired = Vec::new();
     for container in existing_containers {
         let container_id = container.id.unwrap_or_default();
         let labels = container.labels.unwrap_or_default();
+        // Never started, e.g. after a crash between creating and starting it
+        let never_started = container.state.as_deref() == Some("created");
 
         let task_id = labels.get("io.uinta.pando.task-id");
         if let Some(task_id) = task_id {
+            let definition_hash = labels.get("io.uinta.pando.definition-hash");
+            let position = labels
+                .get("io.uinta.pando.start-order")
+                .and_then(|position| position.parse::<usize>().ok())
+                .unwrap_or_default();
+
+            // A task that kept its id but changed anything else gets recreated
             let mut found = false;
             for task in &schedule.containers {
-                if task.id == *task_id {
+                if !never_started
+                    && task.id == *task_id
+                    && definition_hash == Some(&schedule.container_hash(task))
+                {
                     found = true;
-                    currently_running.insert(task_id.clone(), true);
+                    currently_running.insert(task_id.clone(), (position, container_id.clone()));
                     break;
                 }
             }
 
             if !found {
-                println!("Removing container {}", container_id);
-                if let Err(e) = runner.kill_container(&container_id).await {
-                    println!("Error removing container: {:?}", e);
-                }
+                retired.push((position, container_id));
             }
         }
     }
 
+    // Sharing a network stack ties a container to the one it joined, so it goes when that does
+    for task in &start_order {
+        let target_running = task.network_container().map(|target| {
+            schedule
+                .containers
+                .iter()
+                .any(|c| c.name == target && currently_running.contains_key(&c.id))
+        });
+        if target_running == Some(false) {
+            if let Some(running) = currently_running.remove(&task.id) {
+                retired.push(running);
+            }
+        }
+    }
+
+    // Tear down in the reverse of the order the containers were started in
+    retired.sort_by_key(|(position, _)| std::cmp::Reverse(*position));
+    for (_, container_id) in retired {
+        println!("Removing container {}", container_id);
+        if let Err(e) = runner.retire_container(&container_id).await {
+            println!("Error removing container: {:?}", e);
+        }
+    }
+
+    runner.sync_networks(&schedule.networks).await?;
+
     if schedule.id.is_empty() {
         println!("No schedule to run");
         return Ok(());
@@ -213,65 +970,168 @@ async fn apply_schedule(
 
     println!("Running schedule: {}", schedule.id);
 
-    // Start new containers
-    for task in &schedule.containers {
+    // Start new containers, dependencies first. A container whose dependency couldn't be
+    // started isn't started either, and neither is anything depending on it.
+    let mut failed = HashSet::new();
+    for (position, task) in start_order.into_iter().enumerate() {
         if currently_running.contains_key(&task.id) {
             println!("Task {} already running", task.id);
             continue;
         }
 
+        if let Some(dependency) = task.dependencies().find(|d| failed.contains(d)) {
+            println!(
+                "Not running task {}: dependency {} failed to start",
+                task.name, dependency
+            );
+            failed.insert(task.name.as_str());
+            continue;
+        }
+
         println!("Running task: {}", task.name);
 
         if !runner.image_exists_locally(&task.container_image).await? {
-            if let Err(e) = runner.pull_image(&task.container_image).await {
+            // Credentials delivered with the schedule take precedence over the device's own
+            let credentials = credentials_for_image(
+                &task.container_image,
+                schedule
+                    .registry_credentials
+                    .iter()
+                    .chain(runner.registry_credentials.iter()),
+            );
+            if let Err(e) = runner.pull_image(&task.container_image, credentials).await {
                 println!("Error pulling image: {:?}", e);
+                failed.insert(task.name.as_str());
                 continue;
             }
         }
 
-        let env_vars: Vec<String> = task
-            .environment
-            .iter()
-            .map(|e| format!("{}={}", e.key, e.value))
-            .collect();
-
-        let command = if !task.command.is_empty() {
-            Some(task.command.clone())
-        } else {
-            None
-        };
-
         let mut labels = HashMap::new();
+        labels.insert("io.uinta.pando.managed".to_string(), "true".to_string());
         labels.insert("io.uinta.pando.task-id".to_string(), task.id.clone());
         labels.insert("io.uinta.pando.task-name".to_string(), task.name.clone());
         labels.insert(
             "io.uinta.pando.schedule-id".to_string(),
             schedule.id.clone(),
         );
+        labels.insert(
+            "io.uinta.pando.start-order".to_string(),
+            position.to_string(),
+        );
+        labels.insert(
+            "io.uinta.pando.definition-hash".to_string(),
+            schedule.container_hash(task),
+        );
+        for feature in host_features_for(task) {
+            labels.insert(
+                format!("io.uinta.pando.features.{}", feature),
+                "true".to_string(),
+            );
+        }
 
-        match runner
-            .run_container(
-                &task.container_image,
-                command,
-                env_vars,
-                labels,
-                task.bind_docker_socket,
-                task.network_mode == "host",
-                task.ports.clone(),
-            )
-            .await
-        {
+        match runner.run_container(task, labels).await {
             Ok(container_id) => println!("Container {}({}) started", task.id, container_id),
-            Err(e) => println!("Error running container: {:?}", e),
+            Err(e) => {
+                println!("Error running container: {:?}", e);
+                failed.insert(task.name.as_str());
+            }
         }
     }
 
     Ok(())
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-struct SystemStats {
-    cpu_temp: f64,
+/// Talks to the remote device service on behalf of this device.
+#[derive(Debug, Clone)]
+struct DeviceApi {
+    client: DeviceServiceClient<tonic::transport::Channel>,
+    device_id: String,
+}
+
+impl DeviceApi {
+    /// Reports the state of every managed container.
+    async fn report_states(&self, runner: &Runner) -> Result<(), anyhow::Error> {
+        let container_states = runner.collect_container_states().await?;
+
+        // The client is a cheap handle onto a shared channel that reconnects on its own
+        let mut client = self.client.clone();
+        client
+            .report_schedule_state(ReportScheduleStateRequest {
+                device_id: self.device_id.clone(),
+                container_states,
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Fetches the schedule currently assigned to this device, if any.
+    async fn fetch_schedule(&self) -> Result<Option<Schedule>, anyhow::Error> {
+        let mut client = self.client.clone();
+        let response = client
+            .get_schedule(GetScheduleRequest {
+                device_id: self.device_id.clone(),
+            })
+            .await?;
+
+        Ok(response.into_inner().schedule)
+    }
+}
+
+/// Removes images that the schedule no longer references, keeping the `keep_last` most recently
+/// used ones for rollbacks. Returns the number of bytes reclaimed.
+async fn prune_images(
+    runner: &Runner,
+    schedule: &Schedule,
+    ledger: &mut config::Config<ImageLedger>,
+    keep_last: usize,
+) -> Result<i64, anyhow::Error> {
+    let now = chrono::Utc::now().timestamp();
+    for task in &schedule.containers {
+        ledger.data_mut().mark_used(&task.container_image, now);
+    }
+
+    let referenced: HashSet<&str> = schedule
+        .containers
+        .iter()
+        .map(|task| task.container_image.as_str())
+        .collect();
+    let candidates = ledger.data().prune_candidates(&referenced, keep_last);
+
+    let mut removed = 0;
+    let mut reclaimed = 0;
+    for image in candidates {
+        let size = match runner.docker.inspect_image(&image).await {
+            Ok(inspected) => inspected.size.unwrap_or_default(),
+            Err(_) => 0,
+        };
+
+        match runner.remove_image(&image).await {
+            Ok(items) => {
+                // Untagging an image that is still tagged elsewhere frees nothing
+                if items.iter().any(|item| item.deleted.is_some()) {
+                    reclaimed += size;
+                }
+                removed += 1;
+                ledger.data_mut().forget(&image);
+            }
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => ledger.data_mut().forget(&image),
+            // Most likely still in use by a container we don't manage; try again next time
+            Err(e) => println!("Error removing image {}: {:?}", image, e),
+        }
+    }
+
+    ledger.save()?;
+
+    println!(
+        "Pruned {} image(s), reclaimed {:.1} MB",
+        removed,
+        reclaimed as f64 / 1_000_000.0
+    );
+
+    Ok(reclaimed)
 }
 
 #[derive(Debug)]
@@ -279,13 +1139,18 @@ enum MessageSubject {
     SetSchedule,
     GetSchedule,
     GetStats,
+    Logs,
+    Exec,
 }
 
+/// Parses a device-scoped command subject, `pando.devices.<id>.commands.<verb>`.
 fn parse_subject(s: Subject) -> Result<MessageSubject, anyhow::Error> {
     let parts = s.split(".").collect::<Vec<&str>>();
 
-    if parts.len() < 3 {
-        return Err(anyhow::anyhow!("Invalid subject (too short)"));
+    if parts.len() != 5 {
+        return Err(anyhow::anyhow!(
+            "Invalid subject (expected pando.devices.<id>.commands.<verb>)"
+        ));
     }
 
     if parts[0] != "pando" {
@@ -294,16 +1159,24 @@ fn parse_subject(s: Subject) -> Result<MessageSubject, anyhow::Error> {
         ));
     }
 
-    if parts[1] != "commands" {
+    if parts[1] != "devices" {
+        return Err(anyhow::anyhow!(
+            "Unrecognized subject (second segment was not 'devices')"
+        ));
+    }
+
+    if parts[3] != "commands" {
         return Err(anyhow::anyhow!(
-            "Unrecognized subject (second segment was not 'commands')"
+            "Unrecognized subject (fourth segment was not 'commands')"
         ));
     }
 
-    match parts[2] {
-        "run-schedule" => Ok(MessageSubject::SetSchedule),
-        "get-schedule" => Ok(MessageSubject::GetSchedule),
-        "get-stats" => Ok(MessageSubject::GetStats),
+    match parts[4] {
+        nats::RUN_SCHEDULE => Ok(MessageSubject::SetSchedule),
+        nats::GET_SCHEDULE => Ok(MessageSubject::GetSchedule),
+        nats::GET_STATS => Ok(MessageSubject::GetStats),
+        nats::LOGS => Ok(MessageSubject::Logs),
+        nats::EXEC => Ok(MessageSubject::Exec),
         _ => Err(anyhow::anyhow!("Invalid subject")),
     }
 }
@@ -314,7 +1187,482 @@ fn parse_schedule_payload(payload: Bytes) -> Result<Schedule, anyhow::Error> {
     // serde_json::from_slice(&payload).map_err(|e| anyhow::anyhow!(e))
 }
 
-async fn run_scheduler(runner: Runner, device_id: String) -> Result<(), anyhow::Error> {
+/// Sends an event to the requester of a session. Returns false if it could not be sent.
+async fn send_session_event<T: Serialize>(
+    client: &async_nats::Client,
+    reply: &Subject,
+    event: &T,
+) -> bool {
+    let payload = serde_json::to_vec(event).unwrap();
+    match client.publish(reply.clone(), payload.into()).await {
+        Ok(()) => true,
+        Err(e) => {
+            println!("Error sending session event: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Returns true while whoever opened the session behind `reply` still answers pings.
+async fn session_alive(client: &async_nats::Client, reply: &Subject) -> bool {
+    let ping = client.request(nats::session_ping_subject(reply), Bytes::new());
+    matches!(
+        time::timeout(Duration::from_secs(SESSION_PING_TIMEOUT_SECS), ping).await,
+        Ok(Ok(_))
+    )
+}
+
+/// Streams a managed container's logs to `reply` until they run out or, when following, until
+/// the requester stops answering pings.
+async fn stream_logs(
+    runner: Arc<Runner>,
+    client: async_nats::Client,
+    reply: Subject,
+    request: LogsRequest,
+) {
+    let container_id = match runner.find_managed_container(&request.task).await {
+        Ok(Some(container_id)) => container_id,
+        Ok(None) => {
+            let error = format!("No managed container for task '{}'", request.task);
+            send_session_event(&client, &reply, &LogEvent::End { error: Some(error) }).await;
+            return;
+        }
+        Err(e) => {
+            let error = format!("Error finding container: {}", e);
+            send_session_event(&client, &reply, &LogEvent::End { error: Some(error) }).await;
+            return;
+        }
+    };
+
+    if !send_session_event(&client, &reply, &LogEvent::Started).await {
+        return;
+    }
+
+    let options = LogsOptions::<String> {
+        follow: request.follow,
+        stdout: true,
+        stderr: true,
+        since: request.since.unwrap_or_default(),
+        tail: request
+            .tail
+            .map(|tail| tail.to_string())
+            .unwrap_or_else(|| "all".to_string()),
+        ..Default::default()
+    };
+    let mut logs = runner.docker.logs(&container_id, Some(options));
+
+    let mut liveness = time::interval(Duration::from_secs(SESSION_PING_INTERVAL_SECS));
+    // The first tick fires immediately, and the requester has only just asked
+    liveness.tick().await;
+
+    let error = loop {
+        tokio::select! {
+            output = logs.next() => {
+                let (stream, message) = match output {
+                    None => break None,
+                    Some(Err(e)) => break Some(format!("Error reading logs: {}", e)),
+                    Some(Ok(LogOutput::StdErr { message })) => (LogStream::Stderr, message),
+                    Some(Ok(LogOutput::StdOut { message }))
+                    | Some(Ok(LogOutput::Console { message })) => (LogStream::Stdout, message),
+                    Some(Ok(LogOutput::StdIn { .. })) => continue,
+                };
+
+                let event = LogEvent::Output {
+                    stream,
+                    message: String::from_utf8_lossy(&message).into_owned(),
+                };
+                if !send_session_event(&client, &reply, &event).await {
+                    return;
+                }
+            }
+            _ = liveness.tick() => {
+                if !session_alive(&client, &reply).await {
+                    println!("Log stream for '{}' abandoned by requester", request.task);
+                    return;
+                }
+            }
+        }
+    };
+
+    send_session_event(&client, &reply, &LogEvent::End { error }).await;
+}
+
+/// Runs a command in a managed container, relaying its input and output between Docker and the
+/// requester until it exits or the requester stops answering pings.
+///
+/// Docker can't kill an exec, so an abandoned command only gets its stdin closed. Shells and
+/// anything else reading stdin exit on that, but a command that ignores it runs to completion.
+async fn run_exec(
+    runner: Arc<Runner>,
+    client: async_nats::Client,
+    reply: Subject,
+    request: ExecRequest,
+) {
+    let exit = |error: String| ExecEvent::Exit {
+        code: None,
+        error: Some(error),
+    };
+
+    let container_id = match runner.find_managed_container(&request.task).await {
+        Ok(Some(container_id)) => container_id,
+        Ok(None) => {
+            let error = format!("No managed container for task '{}'", request.task);
+            send_session_event(&client, &reply, &exit(error)).await;
+            return;
+        }
+        Err(e) => {
+            let error = format!("Error finding container: {}", e);
+            send_session_event(&client, &reply, &exit(error)).await;
+            return;
+        }
+    };
+
+    // Subscribed before announcing the start so no input is lost
+    let mut inputs = match client.subscribe(nats::session_input_subject(&reply)).await {
+        Ok(inputs) => inputs,
+        Err(e) => {
+            let error = format!("Error subscribing to input: {}", e);
+            send_session_event(&client, &reply, &exit(error)).await;
+            return;
+        }
+    };
+
+    let options = CreateExecOptions {
+        cmd: Some(request.cmd.clone()),
+        attach_stdin: Some(request.interactive),
+        attach_stdout: Some(true),
+        attach_stderr: Some(true),
+        tty: Some(request.tty),
+        ..Default::default()
+    };
+    let exec = match runner.docker.create_exec(&container_id, options).await {
+        Ok(exec) => exec,
+        Err(e) => {
+            let error = format!("Error creating exec: {}", e);
+            send_session_event(&client, &reply, &exit(error)).await;
+            return;
+        }
+    };
+
+    let options = StartExecOptions {
+        detach: false,
+        tty: request.tty,
+        output_capacity: None,
+    };
+    let (mut output, input) = match runner.docker.start_exec(&exec.id, Some(options)).await {
+        Ok(StartExecResults::Attached { output, input }) => (output, input),
+        Ok(StartExecResults::Detached) => {
+            send_session_event(&client, &reply, &exit("Exec detached".to_string())).await;
+            return;
+        }
+        Err(e) => {
+            let error = format!("Error starting exec: {}", e);
+            send_session_event(&client, &reply, &exit(error)).await;
+            return;
+        }
+    };
+    let mut stdin = request.interactive.then_some(input);
+
+    if let (true, Some(size)) = (request.tty, request.size) {
+        runner.resize_exec(&exec.id, size).await;
+    }
+
+    if !send_session_event(&client, &reply, &ExecEvent::Started).await {
+        return;
+    }
+
+    let mut liveness = time::interval(Duration::from_secs(SESSION_PING_INTERVAL_SECS));
+    // The first tick fires immediately, and the requester has only just asked
+    liveness.tick().await;
+
+    // `None` once the requester is gone, otherwise the command finished
+    let finished = loop {
+        tokio::select! {
+            chunk = output.next() => {
+                let (stream, data) = match chunk {
+                    None => break Some(None),
+                    Some(Err(e)) => break Some(Some(format!("Error reading output: {}", e))),
+                    Some(Ok(LogOutput::StdErr { message })) => (LogStream::Stderr, message),
+                    Some(Ok(LogOutput::StdOut { message }))
+                    | Some(Ok(LogOutput::Console { message })) => (LogStream::Stdout, message),
+                    Some(Ok(LogOutput::StdIn { .. })) => continue,
+                };
+
+                let event = ExecEvent::Output {
+                    stream,
+                    data: data.to_vec(),
+                };
+                if !send_session_event(&client, &reply, &event).await {
+                    break None;
+                }
+            }
+            Some(message) = inputs.next() => {
+                match serde_json::from_slice::<ExecInput>(&message.payload) {
+                    Ok(ExecInput::Stdin { data }) => {
+                        if let Some(writer) = stdin.as_mut() {
+                            if let Err(e) = writer.write_all(&data).await {
+                                println!("Error writing exec input: {:?}", e);
+                                stdin = None;
+                            }
+                        }
+                    }
+                    Ok(ExecInput::CloseStdin) => {
+                        if let Some(mut writer) = stdin.take() {
+                            if let Err(e) = writer.shutdown().await {
+                                println!("Error closing exec input: {:?}", e);
+                            }
+                        }
+                    }
+                    Ok(ExecInput::Resize(size)) => runner.resize_exec(&exec.id, size).await,
+                    Err(e) => println!("Ignoring invalid exec input: {:?}", e),
+                }
+            }
+            _ = liveness.tick() => {
+                if !session_alive(&client, &reply).await {
+                    println!("Exec in '{}' abandoned by requester", request.task);
+                    break None;
+                }
+            }
+        }
+    };
+
+    let Some(error) = finished else {
+        if let Some(mut writer) = stdin.take() {
+            if let Err(e) = writer.shutdown().await {
+                println!("Error closing exec input: {:?}", e);
+            }
+        }
+        return;
+    };
+
+    let code = match runner.docker.inspect_exec(&exec.id).await {
+        Ok(inspected) => inspected.exit_code,
+        Err(e) => {
+            println!("Error inspecting exec: {:?}", e);
+            None
+        }
+    };
+
+    send_session_event(&client, &reply, &ExecEvent::Exit { code, error }).await;
+}
+
+/// Converges the device onto schedules, whether pushed over NATS or pulled from the remote.
+struct Scheduler {
+    runner: Arc<Runner>,
+    /// The schedule enforced when containers stop behind our back. Only set once a schedule
+    /// applied cleanly, and cleared when a newer one fails, so a broken schedule isn't retried on
+    /// every container event and the device isn't rolled back to an older one.
+    enforced: Option<Schedule>,
+    image_ledger: config::Config<ImageLedger>,
+    image_keep_last: usize,
+    device_api: Option<DeviceApi>,
+    applied: ScheduleStore,
+}
+
+impl Scheduler {
+    async fn converge(&mut self, schedule: &Schedule) {
+        self.enforced = None;
+
+        match apply_schedule(&self.runner, schedule).await {
+            Ok(()) => {
+                self.enforced = Some(schedule.clone());
+
+                // Supersedes whatever was persisted before, so the next offline boot runs this one
+                if let Err(e) = self.applied.save(schedule) {
+                    println!("Error persisting applied schedule: {:?}", e);
+                }
+
+                if let Err(e) = prune_images(
+                    &self.runner,
+                    schedule,
+                    &mut self.image_ledger,
+                    self.image_keep_last,
+                )
+                .await
+                {
+                    println!("Error pruning images: {:?}", e);
+                }
+            }
+            Err(e) => println!("Error applying schedule: {:?}", e),
+        }
+
+        if let Some(device_api) = &self.device_api {
+            if let Err(e) = device_api.report_states(&self.runner).await {
+                println!("Error reporting container states: {:?}", e);
+            }
+        }
+    }
+
+    /// Sweeps exited containers, then re-applies the schedule persisted by the last successful
+    /// apply, so the workload comes back after a reboot even when the network doesn't.
+    async fn restore_applied_schedule(&mut self) {
+        let persisted = self.applied.load();
+
+        let schedule = persisted.as_ref().ok().and_then(Option::as_ref);
+        if let Err(e) = self.runner.sweep_exited_containers(schedule).await {
+            println!("Error sweeping exited containers: {:?}", e);
+        }
+
+        match persisted {
+            Ok(Some(schedule)) => {
+                println!("Re-applying persisted schedule {}", schedule.id);
+                match apply_schedule(&self.runner, &schedule).await {
+                    Ok(()) => self.enforced = Some(schedule),
+                    Err(e) => println!("Error applying persisted schedule: {:?}", e),
+                }
+            }
+            Ok(None) => debug!("No persisted schedule at {:?}", self.applied.path()),
+            Err(e) => println!("Error loading persisted schedule: {:?}", e),
+        }
+    }
+
+    /// Brings the containers back in line with the enforced schedule after some of them stopped
+    /// or disappeared without us asking.
+    async fn enforce_schedule(&mut self) {
+        let Some(schedule) = &self.enforced else {
+            return;
+        };
+
+        if let Err(e) = self.runner.sweep_exited_containers(Some(schedule)).await {
+            println!("Error sweeping exited containers: {:?}", e);
+        }
+        if let Err(e) = apply_schedule(&self.runner, schedule).await {
+            println!("Error enforcing schedule: {:?}", e);
+        }
+
+        if let Some(device_api) = &self.device_api {
+            if let Err(e) = device_api.report_states(&self.runner).await {
+                println!("Error reporting container states: {:?}", e);
+            }
+        }
+    }
+
+    /// Fetches the assigned schedule and applies it, catching up on anything pushed while the
+    /// device was offline.
+    async fn pull_schedule(&mut self) {
+        let Some(device_api) = &self.device_api else {
+            return;
+        };
+
+        match device_api.fetch_schedule().await {
+            Ok(Some(schedule)) => {
+                println!("Fetched assigned schedule {}", schedule.id);
+                self.converge(&schedule).await;
+            }
+            Ok(None) => println!("No schedule assigned to this device"),
+            Err(e) => println!("Error fetching assigned schedule: {:?}", e),
+        }
+    }
+}
+
+/// Follows the engine's events for managed containers and republishes the ones worth knowing
+/// about. Containers that stopped or disappeared without us retiring them are checked against
+/// their restart policy, and `enforce` is notified if the schedule needs to step in.
+async fn watch_container_events(
+    runner: Arc<Runner>,
+    client: async_nats::Client,
+    device_id: String,
+    enforce: Arc<Notify>,
+) {
+    let subject = nats::device_events_subject(&device_id);
+    let (candidates, pending) = mpsc::unbounded_channel();
+    task::spawn(debounce_enforcement(
+        runner.clone(),
+        pending,
+        enforce.clone(),
+    ));
+
+    loop {
+        let mut filters = HashMap::new();
+        filters.insert("type", vec!["container"]);
+        filters.insert("label", vec!["io.uinta.pando.managed=true"]);
+
+        let mut events = runner.docker.events(Some(EventsOptions {
+            filters,
+            ..Default::default()
+        }));
+
+        while let Some(event) = events.next().await {
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    println!("Error reading engine events: {:?}", e);
+                    break;
+                }
+            };
+            let Some(event) = ContainerEvent::from_docker(&event) else {
+                continue;
+            };
+
+            match event.kind {
+                ContainerEventKind::Die => println!(
+                    "Task {} exited with code {:?}",
+                    event.task_id, event.exit_code
+                ),
+                ContainerEventKind::Oom => println!("Task {} ran out of memory", event.task_id),
+                ContainerEventKind::HealthStatus => println!(
+                    "Task {} is {}",
+                    event.task_id,
+                    event.health.as_deref().unwrap_or_default()
+                ),
+                ContainerEventKind::Destroy => println!("Task {} was removed", event.task_id),
+            }
+
+            let event_json = serde_json::to_string(&event).unwrap();
+            if let Err(e) = client.publish(subject.clone(), event_json.into()).await {
+                println!("Error publishing container event: {:?}", e);
+            }
+
+            if matches!(
+                event.kind,
+                ContainerEventKind::Die | ContainerEventKind::Destroy
+            ) && !runner.is_retiring(&event)
+            {
+                let _ = candidates.send(event);
+            }
+        }
+
+        println!("Engine event stream ended; resubscribing");
+        time::sleep(Duration::from_secs(EVENTS_RETRY_SECS)).await;
+        // Whatever happened in the meantime went unseen
+        enforce.notify_one();
+    }
+}
+
+/// Collects bursts of stopped containers, gives the engine time to apply their restart policies,
+/// then asks for the schedule to be enforced at most once per burst.
+async fn debounce_enforcement(
+    runner: Arc<Runner>,
+    mut pending: mpsc::UnboundedReceiver<ContainerEvent>,
+    enforce: Arc<Notify>,
+) {
+    while let Some(first) = pending.recv().await {
+        time::sleep(Duration::from_secs(ENFORCE_DELAY_SECS)).await;
+
+        let mut burst = vec![first];
+        while let Ok(event) = pending.try_recv() {
+            burst.push(event);
+        }
+
+        for event in &burst {
+            if runner.needs_enforcement(event).await {
+                println!("Task {} stopped outside its restart policy", event.task_id);
+                enforce.notify_one();
+                break;
+            }
+        }
+    }
+}
+
+async fn run_scheduler(mut scheduler: Scheduler, device_id: String) -> Result<(), anyhow::Error> {
+    scheduler.restore_applied_schedule().await;
+    scheduler.pull_schedule().await;
+
+    // async-nats reconnects on its own, but anything published while we were away is lost, so
+    // every reconnect triggers a fresh pull
+    let reconnected = Arc::new(Notify::new());
+    let connected_once = Arc::new(AtomicBool::new(false));
+
     // let nats_url = env::var("NATS_URL").unwrap_or_else(|_| "tls://connect.ngs.global".to_string());
     let nats_url = env::var("NATS_URL").unwrap_or_else(|_| "mqtt.stag9.com".to_string());
     // let client = async_nats::ConnectOptions::with_credentials_file(
@@ -325,67 +1673,232 @@ async fn run_scheduler(runner: Runner, device_id: String) -> Result<(), anyhow::
         // .await
         // .expect("Failed to create client")
         .name(format!("pando-agent-{}", device_id))
+        .event_callback({
+            let reconnected = reconnected.clone();
+            let connected_once = connected_once.clone();
+            move |event| {
+                let reconnected = reconnected.clone();
+                let connected_once = connected_once.clone();
+                async move {
+                    if let async_nats::Event::Connected = event {
+                        if connected_once.swap(true, Ordering::SeqCst) {
+                            reconnected.notify_one();
+                        }
+                    }
+                }
+            }
+        })
         .connect(nats_url)
         .await?;
-    let mut subscriber = client.subscribe("pando.commands.*").await?;
 
+    let mut subscriber = client
+        .subscribe(nats::device_command_subject(&device_id, "*"))
+        .await?;
+
+    let stats_interval = Duration::from_secs(
+        env::var("PANDO_STATS_INTERVAL_SECS")
+            .ok()
+            .and_then(|interval| interval.parse().ok())
+            .filter(|interval| *interval > 0)
+            .unwrap_or(DEFAULT_STATS_INTERVAL_SECS),
+    );
+    let stats_client = client.clone();
+    let stats_subject = format!("pando.stats.{}.json", device_id);
+    let container_stats_subject = format!("pando.stats.{}.containers.json", device_id);
+    let stats_runner = scheduler.runner.clone();
     task::spawn(async move {
         loop {
-            match list_zones().await {
-                Ok(temp_zones) => {
-                    for zone in temp_zones {
-                        let temp = Temperature::new(zone.clone());
-                        let temp = temp.get_temperature().await.unwrap();
-                        let stats = SystemStats { cpu_temp: temp };
-                        let stats_json = serde_json::to_string(&stats).unwrap();
-                        println!("Publishing stats: {}", stats_json);
-
-                        let subject = format!("pando.stats.{}.json", device_id);
-                        if let Err(e) = client.publish(subject, stats_json.into()).await {
-                            println!("Error publishing stats: {:?}", e);
-                        }
-                        time::sleep(Duration::from_secs(5)).await;
+            match SystemStats::collect().await {
+                Ok(stats) => {
+                    let stats_json = serde_json::to_string(&stats).unwrap();
+                    debug!("Publishing stats: {}", stats_json);
+
+                    if let Err(e) = stats_client
+                        .publish(stats_subject.clone(), stats_json.into())
+                        .await
+                    {
+                        println!("Error publishing stats: {:?}", e);
                     }
                 }
-                Err(e) => println!("Error getting temperature: {:?}", e),
+                Err(e) => println!("Error collecting stats: {:?}", e),
             }
+
+            match stats_runner.collect_container_stats().await {
+                Ok(containers) => {
+                    let report = ContainerStatsReport {
+                        version: STATS_VERSION,
+                        timestamp: chrono::Utc::now().timestamp(),
+                        containers,
+                    };
+                    let report_json = serde_json::to_string(&report).unwrap();
+                    debug!("Publishing container stats: {}", report_json);
+
+                    if let Err(e) = stats_client
+                        .publish(container_stats_subject.clone(), report_json.into())
+                        .await
+                    {
+                        println!("Error publishing container stats: {:?}", e);
+                    }
+                }
+                Err(e) => println!("Error collecting container stats: {:?}", e),
+            }
+
+            time::sleep(stats_interval).await;
         }
     });
 
+    if let Some(device_api) = scheduler.device_api.clone() {
+        let runner = scheduler.runner.clone();
+        task::spawn(async move {
+            loop {
+                time::sleep(Duration::from_secs(STATE_REPORT_INTERVAL_SECS)).await;
+                if let Err(e) = device_api.report_states(&runner).await {
+                    println!("Error reporting container states: {:?}", e);
+                }
+            }
+        });
+    }
+
+    let enforce = Arc::new(Notify::new());
+    task::spawn(watch_container_events(
+        scheduler.runner.clone(),
+        client.clone(),
+        device_id.clone(),
+        enforce.clone(),
+    ));
+
     loop {
-        while let Some(message) = subscriber.next().await {
-            println!("Received message {:?}", message);
+        let message = tokio::select! {
+            message = subscriber.next() => message,
+            _ = reconnected.notified() => {
+                println!("Reconnected to NATS; pulling assigned schedule");
+                scheduler.pull_schedule().await;
+                continue;
+            }
+            _ = enforce.notified() => {
+                scheduler.enforce_schedule().await;
+                continue;
+            }
+        };
 
-            match parse_subject(message.subject.clone()) {
-                Err(e) => {
-                    println!("Error parsing subject: {:?}", e);
-                    continue;
-                }
-                Ok(subject) => match subject {
-                    MessageSubject::SetSchedule => match parse_schedule_payload(message.payload) {
+        let Some(message) = message else {
+            println!("Subscriber closed. Attempting to reconnect...");
+            time::sleep(Duration::from_secs(5)).await;
+            continue;
+        };
+
+        // The payload may carry registry credentials, so only the subject is logged
+        println!("Received message on {}", message.subject);
+
+        match parse_subject(message.subject.clone()) {
+            Err(e) => {
+                println!("Error parsing subject: {:?}", e);
+                continue;
+            }
+            Ok(subject) => match subject {
+                MessageSubject::SetSchedule => match parse_schedule_payload(message.payload) {
+                    Err(e) => {
+                        println!("Error parsing schedule payload: {:?}", e);
+                        continue;
+                    }
+                    Ok(schedule) => {
+                        scheduler.converge(&schedule).await;
+                        println!("Received schedule: {:?}", schedule);
+                    }
+                },
+                MessageSubject::GetSchedule => {
+                    let Some(reply) = message.reply else {
+                        println!("Ignoring get-schedule without a reply subject");
+                        continue;
+                    };
+
+                    let payload = match scheduler.applied.load() {
+                        Ok(Some(mut schedule)) => {
+                            // Whoever is asking has no business seeing registry passwords
+                            schedule.registry_credentials.clear();
+                            schedule.encode_to_vec()
+                        }
+                        // An empty reply tells the requester nothing has been applied yet
+                        Ok(None) => vec![],
                         Err(e) => {
-                            println!("Error parsing schedule payload: {:?}", e);
+                            println!("Error loading applied schedule: {:?}", e);
                             continue;
                         }
-                        Ok(schedule) => {
-                            if let Err(e) = apply_schedule(&runner, &schedule).await {
-                                println!("Error applying schedule: {:?}", e);
+                    };
+
+                    if let Err(e) = client.publish(reply, payload.into()).await {
+                        println!("Error replying to get-schedule: {:?}", e);
+                    }
+                }
+                MessageSubject::GetStats => {
+                    let Some(reply) = message.reply else {
+                        println!("Ignoring get-stats without a reply subject");
+                        continue;
+                    };
+
+                    match SystemStats::collect().await {
+                        Ok(stats) => {
+                            let stats_json = serde_json::to_vec(&stats).unwrap();
+                            if let Err(e) = client.publish(reply, stats_json.into()).await {
+                                println!("Error replying to get-stats: {:?}", e);
                             }
+                        }
+                        Err(e) => println!("Error collecting stats: {:?}", e),
+                    }
+                }
+                MessageSubject::Logs => {
+                    let Some(reply) = message.reply else {
+                        println!("Ignoring logs request without a reply subject");
+                        continue;
+                    };
 
-                            println!("Received schedule: {:?}", schedule);
+                    match serde_json::from_slice::<LogsRequest>(&message.payload) {
+                        Ok(request) => {
+                            task::spawn(stream_logs(
+                                scheduler.runner.clone(),
+                                client.clone(),
+                                reply,
+                                request,
+                            ));
+                        }
+                        Err(e) => {
+                            let error = format!("Invalid logs request: {}", e);
+                            send_session_event(
+                                &client,
+                                &reply,
+                                &LogEvent::End { error: Some(error) },
+                            )
+                            .await;
                         }
-                    },
-                    MessageSubject::GetSchedule => {
-                        println!("Received get-schedule request (unhandled)");
                     }
-                    MessageSubject::GetStats => {
-                        println!("Received get-stats request (unhandled)");
+                }
+                MessageSubject::Exec => {
+                    let Some(reply) = message.reply else {
+                        println!("Ignoring exec request without a reply subject");
+                        continue;
+                    };
+
+                    match serde_json::from_slice::<ExecRequest>(&message.payload) {
+                        Ok(request) => {
+                            println!("Running {:?} in '{}'", request.cmd, request.task);
+                            task::spawn(run_exec(
+                                scheduler.runner.clone(),
+                                client.clone(),
+                                reply,
+                                request,
+                            ));
+                        }
+                        Err(e) => {
+                            let exit = ExecEvent::Exit {
+                                code: None,
+                                error: Some(format!("Invalid exec request: {}", e)),
+                            };
+                            send_session_event(&client, &reply, &exit).await;
+                        }
                     }
-                },
-            }
+                }
+            },
         }
-        println!("Subscriber closed. Attempting to reconnect...");
-        time::sleep(Duration::from_secs(5)).await;
     }
 }
 
@@ -393,19 +1906,18 @@ pub async fn run_agent() -> Result<(), anyhow::Error> {
     let docker_engine_socket =
         env::var("DOCKER_HOST").unwrap_or_else(|_| DEFAULT_DOCKER_ENGINE_SOCKET.to_string());
 
-    let runner = Runner::new(&docker_engine_socket).await?;
+    let host_boot_path =
+        env::var("HOST_BOOT_PATH").unwrap_or_else(|_| DEFAULT_HOST_BOOT_PATH.to_string());
+
+    let mut runner = Runner::new(&docker_engine_socket, &host_boot_path).await?;
 
-    let uname = rustix::system::uname();
-    let hostname = uname.nodename().to_str().unwrap_or("unknown");
-    let device_id = hostname.to_string();
+    #[cfg(target_os = "linux")]
+    let config_mode = config::ConfigMode::Path("/boot/config.json".into());
+    #[cfg(not(target_os = "linux"))]
+    let config_mode = config::ConfigMode::User;
 
-    let mut config_manager = config::Config::<ConfigJson>::new(
-        #[cfg(target_os = "linux")]
-        config::ConfigMode::Path("/boot/config.json".into()),
-        #[cfg(not(target_os = "linux"))]
-        config::ConfigMode::User,
-        "config.json".into(),
-    )?;
+    let mut config_manager =
+        config::Config::<ConfigJson>::new(config_mode.clone(), "config.json".into())?;
     let config_json = config_manager.setup()?;
 
     if registration::get_registration_status(&config_json) {
@@ -509,5 +2021,94 @@ pub async fn run_agent() -> Result<(), anyhow::Error> {
         config_manager.save()?;
     }
 
-    run_scheduler(runner, device_id).await
+    runner.registry_credentials = config_manager
+        .data()
+        .registry_credentials
+        .clone()
+        .unwrap_or_default();
+
+    // Kept next to config.json so they survive agent updates
+    let image_ledger = config::Config::<ImageLedger>::new(config_mode, "images.json".into())?;
+    let applied = ScheduleStore::new(config_manager.config_dir(), "schedule.pb");
+    let image_keep_last = env::var("PANDO_IMAGE_KEEP_LAST")
+        .ok()
+        .and_then(|keep_last| keep_last.parse().ok())
+        .unwrap_or(DEFAULT_IMAGE_KEEP_LAST);
+
+    let device_api = match (
+        config_manager.data().api_endpoint.clone(),
+        config_manager.data().uuid.clone(),
+    ) {
+        (Some(api_endpoint), Some(uuid)) if !api_endpoint.is_empty() && !uuid.is_empty() => {
+            // Without a deadline a hung server would stall the scheduler loop
+            let channel = tonic::transport::Channel::from_shared(api_endpoint)?
+                .connect_timeout(Duration::from_secs(DEVICE_API_TIMEOUT_SECS))
+                .timeout(Duration::from_secs(DEVICE_API_TIMEOUT_SECS))
+                .connect_lazy();
+            Some(DeviceApi {
+                client: DeviceServiceClient::new(channel),
+                device_id: uuid,
+            })
+        }
+        _ => {
+            println!(
+                "Device has no API endpoint or identifier; schedules won't be pulled and container states won't be reported"
+            );
+            None
+        }
+    };
+
+    // Commands are addressed to the identifier the remote knows us by; unregistered devices fall
+    // back to their hostname
+    let device_id = match config_manager.data().uuid.clone() {
+        Some(uuid) if !uuid.is_empty() => uuid,
+        _ => {
+            let uname = rustix::system::uname();
+            uname.nodename().to_str().unwrap_or("unknown").to_string()
+        }
+    };
+
+    let scheduler = Scheduler {
+        runner: Arc::new(runner),
+        enforced: None,
+        image_ledger,
+        image_keep_last,
+        device_api,
+        applied,
+    };
+
+    run_scheduler(scheduler, device_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_subject() {
+        let device_id = "0192b1c2-0000-7000-8000-000000000001";
+
+        assert!(matches!(
+            parse_subject(nats::device_command_subject(device_id, nats::RUN_SCHEDULE).into()),
+            Ok(MessageSubject::SetSchedule)
+        ));
+        assert!(parse_subject("pando.fleets.kiosks.commands.get-stats".into()).is_err());
+        assert!(matches!(
+            parse_subject(nats::device_command_subject(device_id, nats::GET_SCHEDULE).into()),
+            Ok(MessageSubject::GetSchedule)
+        ));
+        assert!(matches!(
+            parse_subject(nats::device_command_subject(device_id, nats::LOGS).into()),
+            Ok(MessageSubject::Logs)
+        ));
+        assert!(matches!(
+            parse_subject(nats::device_command_subject(device_id, nats::EXEC).into()),
+            Ok(MessageSubject::Exec)
+        ));
+
+        assert!(parse_subject("pando.commands.run-schedule".into()).is_err());
+        assert!(parse_subject("pando.devices.abc.events.run-schedule".into()).is_err());
+        assert!(parse_subject("pando.groups.abc.commands.run-schedule".into()).is_err());
+        assert!(parse_subject("pando.devices.abc.commands.reboot".into()).is_err());
+    }
 }
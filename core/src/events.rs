@@ -0,0 +1,124 @@
+use bollard::models::EventMessage;
+use serde::{Deserialize, Serialize};
+
+/// Bumped whenever the shape of `ContainerEvent` changes incompatibly.
+pub const EVENTS_VERSION: u32 = 1;
+
+/// Something that happened to a managed container, published on the device's events subject as
+/// the engine reports it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContainerEvent {
+    pub version: u32,
+    /// Unix timestamp of the event
+    pub timestamp: i64,
+    pub kind: ContainerEventKind,
+    pub task_id: String,
+    pub name: String,
+    pub container_id: String,
+    /// Only set for `die`
+    pub exit_code: Option<i64>,
+    /// Only set for `health_status`, e.g. `unhealthy`
+    pub health: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContainerEventKind {
+    /// The main process exited
+    Die,
+    /// The kernel killed a process for running out of memory
+    Oom,
+    HealthStatus,
+    /// The container was removed
+    Destroy,
+}
+
+impl ContainerEvent {
+    /// Picks out the engine events worth reporting; anything else is `None`.
+    pub fn from_docker(event: &EventMessage) -> Option<Self> {
+        let action = event.action.as_deref()?;
+        let actor = event.actor.as_ref()?;
+        let attributes = actor.attributes.clone().unwrap_or_default();
+        let attribute = |key: &str| attributes.get(key).cloned().unwrap_or_default();
+
+        // Health changes arrive as e.g. "health_status: unhealthy"
+        let (kind, health) = match action.split_once(':') {
+            Some(("health_status", health)) => (
+                ContainerEventKind::HealthStatus,
+                Some(health.trim().to_string()),
+            ),
+            Some(_) => return None,
+            None => match action {
+                "die" => (ContainerEventKind::Die, None),
+                "oom" => (ContainerEventKind::Oom, None),
+                "destroy" => (ContainerEventKind::Destroy, None),
+                _ => return None,
+            },
+        };
+
+        Some(ContainerEvent {
+            version: EVENTS_VERSION,
+            timestamp: event.time.unwrap_or_default(),
+            kind,
+            task_id: attribute("io.uinta.pando.task-id"),
+            name: attribute("io.uinta.pando.task-name"),
+            container_id: actor.id.clone().unwrap_or_default(),
+            exit_code: match kind {
+                ContainerEventKind::Die => attribute("exitCode").parse().ok(),
+                _ => None,
+            },
+            health,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bollard::models::EventActor;
+    use std::collections::HashMap;
+
+    fn docker_event(action: &str, attributes: &[(&str, &str)]) -> EventMessage {
+        let mut attributes: HashMap<String, String> = attributes
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        attributes.insert("io.uinta.pando.task-id".to_string(), "web".to_string());
+        attributes.insert("io.uinta.pando.task-name".to_string(), "nginx".to_string());
+
+        EventMessage {
+            action: Some(action.to_string()),
+            actor: Some(EventActor {
+                id: Some("abc123".to_string()),
+                attributes: Some(attributes),
+            }),
+            time: Some(1700000000),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_from_docker() {
+        let event =
+            ContainerEvent::from_docker(&docker_event("die", &[("exitCode", "137")])).unwrap();
+        assert_eq!(event.kind, ContainerEventKind::Die);
+        assert_eq!(event.task_id, "web");
+        assert_eq!(event.name, "nginx");
+        assert_eq!(event.container_id, "abc123");
+        assert_eq!(event.exit_code, Some(137));
+        assert_eq!(event.timestamp, 1700000000);
+
+        let event =
+            ContainerEvent::from_docker(&docker_event("health_status: unhealthy", &[])).unwrap();
+        assert_eq!(event.kind, ContainerEventKind::HealthStatus);
+        assert_eq!(event.health.as_deref(), Some("unhealthy"));
+        assert_eq!(event.exit_code, None);
+
+        assert!(ContainerEvent::from_docker(&docker_event("start", &[])).is_none());
+        assert!(ContainerEvent::from_docker(&docker_event("exec_start: sh", &[])).is_none());
+
+        let json =
+            serde_json::to_string(&ContainerEvent::from_docker(&docker_event("oom", &[]))).unwrap();
+        assert!(json.contains(r#""kind":"oom""#), "{}", json);
+    }
+}
@@ -0,0 +1,112 @@
+use serde::{Deserialize, Serialize};
+
+use crate::logs::LogStream;
+
+/// Asks the agent to run a command inside a managed container. Sent as JSON on the device's
+/// `exec` subject with a reply inbox that receives `ExecEvent`s. Once `ExecEvent::Started`
+/// arrives, `ExecInput`s can be published to the inbox's input subject.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExecRequest {
+    /// Task id or service name
+    pub task: String,
+    pub cmd: Vec<String>,
+    /// Attach stdin to the command
+    pub interactive: bool,
+    /// Allocate a pseudo-terminal; output then arrives as a single stdout stream
+    pub tty: bool,
+    /// Initial terminal size when `tty` is set
+    pub size: Option<TerminalSize>,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TerminalSize {
+    pub rows: u16,
+    pub cols: u16,
+}
+
+/// Sent by the requester while the command runs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ExecInput {
+    Stdin {
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    CloseStdin,
+    Resize(TerminalSize),
+}
+
+/// Sent by the agent to the requester's inbox.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ExecEvent {
+    /// The command is running and input is accepted
+    Started,
+    Output {
+        stream: LogStream,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    /// No more events follow. `code` is unset if the command couldn't be run or its exit code is
+    /// unknown, in which case `error` says why.
+    Exit {
+        code: Option<i64>,
+        error: Option<String>,
+    },
+}
+
+/// Carries raw bytes as a base64 string, which is far smaller in JSON than an array of numbers.
+mod base64_bytes {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exec_messages_json() {
+        let input = ExecInput::Resize(TerminalSize { rows: 24, cols: 80 });
+        let json = serde_json::to_string(&input).unwrap();
+        assert_eq!(json, r#"{"type":"resize","rows":24,"cols":80}"#);
+        assert_eq!(serde_json::from_str::<ExecInput>(&json).unwrap(), input);
+
+        let input = ExecInput::Stdin {
+            data: b"ls\n".to_vec(),
+        };
+        let json = serde_json::to_string(&input).unwrap();
+        assert_eq!(json, r#"{"type":"stdin","data":"bHMK"}"#);
+        assert_eq!(serde_json::from_str::<ExecInput>(&json).unwrap(), input);
+
+        let event = ExecEvent::Output {
+            stream: LogStream::Stderr,
+            data: vec![0xff, 0x00, 0x1b],
+        };
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(json, r#"{"type":"output","stream":"stderr","data":"/wAb"}"#);
+        assert_eq!(serde_json::from_str::<ExecEvent>(&json).unwrap(), event);
+        assert!(serde_json::from_str::<ExecEvent>(
+            r#"{"type":"output","stream":"stdout","data":"not base64!"}"#
+        )
+        .is_err());
+
+        let event = ExecEvent::Exit {
+            code: Some(127),
+            error: None,
+        };
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(json, r#"{"type":"exit","code":127,"error":null}"#);
+        assert_eq!(serde_json::from_str::<ExecEvent>(&json).unwrap(), event);
+    }
+}
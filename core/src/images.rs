@@ -0,0 +1,71 @@
+use std::collections::{BTreeMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+/// Remembers which images schedules have used on this device, and when each was last used, so
+/// that pruning only ever touches images the agent brought onto the device.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageLedger {
+    /// Image reference to the unix timestamp of the last schedule that used it
+    #[serde(default)]
+    pub images: BTreeMap<String, i64>,
+}
+
+impl ImageLedger {
+    pub fn mark_used(&mut self, image: &str, timestamp: i64) {
+        self.images.insert(image.to_string(), timestamp);
+    }
+
+    pub fn forget(&mut self, image: &str) {
+        self.images.remove(image);
+    }
+
+    /// Images no longer referenced by the current schedule, minus the `keep_last` most recently
+    /// used of those, which stay around so a rollback doesn't need a fresh pull.
+    pub fn prune_candidates(&self, referenced: &HashSet<&str>, keep_last: usize) -> Vec<String> {
+        let mut unreferenced: Vec<(&String, &i64)> = self
+            .images
+            .iter()
+            .filter(|(image, _)| !referenced.contains(image.as_str()))
+            .collect();
+
+        // Most recently used first; ties fall back to the name so the result is deterministic
+        unreferenced.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+
+        unreferenced
+            .into_iter()
+            .skip(keep_last)
+            .map(|(image, _)| image.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune_candidates() {
+        let mut ledger = ImageLedger::default();
+        ledger.mark_used("app:1", 100);
+        ledger.mark_used("app:2", 200);
+        ledger.mark_used("app:3", 300);
+        ledger.mark_used("app:4", 400);
+        ledger.mark_used("db:16", 400);
+
+        let referenced: HashSet<&str> = ["app:4", "db:16"].into_iter().collect();
+
+        assert_eq!(
+            ledger.prune_candidates(&referenced, 0),
+            vec!["app:3", "app:2", "app:1"]
+        );
+        assert_eq!(
+            ledger.prune_candidates(&referenced, 1),
+            vec!["app:2", "app:1"]
+        );
+        assert!(ledger.prune_candidates(&referenced, 5).is_empty());
+
+        ledger.forget("app:1");
+        assert_eq!(ledger.prune_candidates(&referenced, 1), vec!["app:2"]);
+    }
+}
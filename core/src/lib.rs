@@ -2,11 +2,18 @@ pub mod config;
 pub mod config_json;
 pub mod config_txt;
 pub mod daemon;
+pub mod events;
+pub mod exec;
+pub mod images;
+pub mod logs;
 pub mod mqtt;
+pub mod nats;
 pub mod registration;
+pub mod registry;
 pub mod schedule;
+pub mod schedule_store;
+pub mod stats;
 pub mod temp;
-pub mod nats;
 
 pub mod grpc_remote {
     tonic::include_proto!("remote.upd88.com");
@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+/// Asks the agent to stream a managed container's logs. Sent as JSON on the device's `logs`
+/// subject with a reply inbox that receives `LogEvent`s.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogsRequest {
+    /// Task id or service name
+    pub task: String,
+    pub follow: bool,
+    /// Number of lines from the end of the log to start with; everything when unset
+    pub tail: Option<u64>,
+    /// Unix timestamp; only lines logged after it are returned
+    pub since: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum LogEvent {
+    /// The container was found and output follows
+    Started,
+    Output {
+        stream: LogStream,
+        message: String,
+    },
+    /// No more events follow. Carries the reason if the stream ended early.
+    End {
+        error: Option<String>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_event_json() {
+        let event = LogEvent::Output {
+            stream: LogStream::Stderr,
+            message: "boom\n".to_string(),
+        };
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"output","stream":"stderr","message":"boom\n"}"#
+        );
+        assert_eq!(serde_json::from_str::<LogEvent>(&json).unwrap(), event);
+
+        assert_eq!(
+            serde_json::to_string(&LogEvent::End { error: None }).unwrap(),
+            r#"{"type":"end","error":null}"#
+        );
+    }
+}
@@ -1,6 +1,103 @@
+use std::time::Duration;
+
+use futures::StreamExt;
 use prost::Message;
 
+use crate::exec::{ExecEvent, ExecInput, ExecRequest};
 use crate::grpc_remote::Schedule;
+use crate::logs::{LogEvent, LogStream, LogsRequest};
+use crate::stats::SystemStats;
+
+/// Command verbs understood by the agent.
+pub const RUN_SCHEDULE: &str = "run-schedule";
+pub const GET_SCHEDULE: &str = "get-schedule";
+pub const GET_STATS: &str = "get-stats";
+pub const LOGS: &str = "logs";
+pub const EXEC: &str = "exec";
+
+/// Subject a single device listens on for `verb`, e.g. `pando.devices.<id>.commands.run-schedule`.
+pub fn device_command_subject(device_id: &str, verb: &str) -> String {
+    format!("pando.devices.{}.commands.{}", device_id, verb)
+}
+
+/// Subject a device publishes `ContainerEvent`s on as JSON.
+pub fn device_events_subject(device_id: &str) -> String {
+    format!("pando.devices.{}.events", device_id)
+}
+
+/// Long-running sessions (e.g. following logs) reply to an inbox. The requester answers pings on
+/// this subject for as long as it wants the session to continue.
+pub fn session_ping_subject(inbox: &str) -> String {
+    format!("{}.ping", inbox)
+}
+
+/// Sessions that take input (e.g. exec) receive it on this subject.
+pub fn session_input_subject(inbox: &str) -> String {
+    format!("{}.input", inbox)
+}
+
+/// Connection name for a requester holding a session open, so it can be told apart on the server.
+fn session_client_name() -> String {
+    format!("pando-cli-{}", std::process::id())
+}
+
+/// A command running on a device, started with `Client::exec`.
+pub struct ExecSession {
+    client: async_nats::Client,
+    events: async_nats::Subscriber,
+    pings: async_nats::Subscriber,
+    input: ExecInputSender,
+    exited: bool,
+}
+
+impl ExecSession {
+    /// A handle for sending input while events are being read.
+    pub fn input(&self) -> ExecInputSender {
+        self.input.clone()
+    }
+
+    /// Waits for the next event from the device, keeping the session alive meanwhile. Returns
+    /// `None` after `ExecEvent::Exit`.
+    pub async fn next_event(&mut self) -> Result<Option<ExecEvent>, anyhow::Error> {
+        if self.exited {
+            return Ok(None);
+        }
+
+        loop {
+            tokio::select! {
+                message = self.events.next() => {
+                    let Some(message) = message else {
+                        return Err(anyhow::anyhow!("Connection closed before the command exited"));
+                    };
+                    let event: ExecEvent = serde_json::from_slice(&message.payload)?;
+                    self.exited = matches!(event, ExecEvent::Exit { .. });
+                    return Ok(Some(event));
+                }
+                Some(ping) = self.pings.next() => {
+                    if let Some(reply) = ping.reply {
+                        self.client.publish(reply, bytes::Bytes::new()).await?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExecInputSender {
+    client: async_nats::Client,
+    subject: String,
+}
+
+impl ExecInputSender {
+    pub async fn send(&self, input: &ExecInput) -> Result<(), anyhow::Error> {
+        self.client
+            .publish(self.subject.clone(), serde_json::to_vec(input)?.into())
+            .await?;
+        self.client.flush().await?;
+        Ok(())
+    }
+}
 
 // TODO: Reconsider this wrapper
 
@@ -16,9 +113,182 @@ impl Client {
 
     pub async fn shutdown(&self) {}
 
+    /// Publishes a schedule to one device. The message carries the schedule's registry
+    /// credentials in plaintext, so the subject must only be readable by that device.
     pub async fn emit_schedule(
         &self,
-        _device_id: String,
+        device_id: String,
+        schedule: &Schedule,
+    ) -> Result<(), anyhow::Error> {
+        self.publish_schedule(device_command_subject(&device_id, RUN_SCHEDULE), schedule)
+            .await
+    }
+
+    /// Asks a live device for the schedule it last applied. Returns `None` if it hasn't applied
+    /// one yet.
+    pub async fn get_schedule(
+        &self,
+        device_id: &str,
+        timeout: Duration,
+    ) -> Result<Option<Schedule>, anyhow::Error> {
+        let payload = self
+            .request(device_command_subject(device_id, GET_SCHEDULE), timeout)
+            .await?;
+
+        if payload.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Schedule::decode(payload)?))
+    }
+
+    /// Asks a live device for a fresh stats snapshot.
+    pub async fn get_stats(
+        &self,
+        device_id: &str,
+        timeout: Duration,
+    ) -> Result<SystemStats, anyhow::Error> {
+        let payload = self
+            .request(device_command_subject(device_id, GET_STATS), timeout)
+            .await?;
+
+        Ok(serde_json::from_slice(&payload)?)
+    }
+
+    /// Streams a container's logs from a live device, handing each chunk to `on_output` until
+    /// the device ends the stream. `timeout` bounds how long to wait for the device to respond.
+    pub async fn stream_logs(
+        &self,
+        device_id: &str,
+        request: &LogsRequest,
+        timeout: Duration,
+        mut on_output: impl FnMut(LogStream, &str),
+    ) -> Result<(), anyhow::Error> {
+        let client = async_nats::ConnectOptions::new()
+            .name(session_client_name())
+            .connect(self.endpoint.clone())
+            .await?;
+
+        let inbox = client.new_inbox();
+        let mut events = client.subscribe(inbox.clone()).await?;
+        let mut pings = client.subscribe(session_ping_subject(&inbox)).await?;
+
+        client
+            .publish_with_reply(
+                device_command_subject(device_id, LOGS),
+                inbox,
+                serde_json::to_vec(request)?.into(),
+            )
+            .await?;
+        client.flush().await?;
+
+        let mut started = false;
+        loop {
+            let message = tokio::select! {
+                message = events.next() => message,
+                Some(ping) = pings.next() => {
+                    if let Some(reply) = ping.reply {
+                        client.publish(reply, bytes::Bytes::new()).await?;
+                    }
+                    continue;
+                }
+                _ = tokio::time::sleep(timeout), if !started => {
+                    return Err(anyhow::anyhow!("Timed out waiting for device {}", device_id));
+                }
+            };
+
+            let Some(message) = message else {
+                return Err(anyhow::anyhow!(
+                    "Connection closed before the log stream ended"
+                ));
+            };
+
+            match serde_json::from_slice(&message.payload)? {
+                LogEvent::Started => started = true,
+                LogEvent::Output { stream, message } => on_output(stream, &message),
+                LogEvent::End { error: None } => return Ok(()),
+                LogEvent::End { error: Some(error) } => return Err(anyhow::anyhow!(error)),
+            }
+        }
+    }
+
+    /// Starts a command in a container on a live device. Fails if the device doesn't respond
+    /// within `timeout` or can't run the command.
+    pub async fn exec(
+        &self,
+        device_id: &str,
+        request: &ExecRequest,
+        timeout: Duration,
+    ) -> Result<ExecSession, anyhow::Error> {
+        let client = async_nats::ConnectOptions::new()
+            .name(session_client_name())
+            .connect(self.endpoint.clone())
+            .await?;
+
+        let inbox = client.new_inbox();
+        let events = client.subscribe(inbox.clone()).await?;
+        let pings = client.subscribe(session_ping_subject(&inbox)).await?;
+
+        client
+            .publish_with_reply(
+                device_command_subject(device_id, EXEC),
+                inbox.clone(),
+                serde_json::to_vec(request)?.into(),
+            )
+            .await?;
+        client.flush().await?;
+
+        let mut session = ExecSession {
+            client: client.clone(),
+            events,
+            pings,
+            input: ExecInputSender {
+                client,
+                subject: session_input_subject(&inbox),
+            },
+            exited: false,
+        };
+
+        match tokio::time::timeout(timeout, session.next_event()).await {
+            Err(_) => Err(anyhow::anyhow!(
+                "Timed out waiting for device {}",
+                device_id
+            )),
+            Ok(event) => match event? {
+                Some(ExecEvent::Started) => Ok(session),
+                Some(ExecEvent::Exit {
+                    error: Some(error), ..
+                }) => Err(anyhow::anyhow!(error)),
+                event => Err(anyhow::anyhow!(
+                    "Unexpected response from device: {:?}",
+                    event
+                )),
+            },
+        }
+    }
+
+    async fn request(
+        &self,
+        subject: String,
+        timeout: Duration,
+    ) -> Result<bytes::Bytes, anyhow::Error> {
+        let client = async_nats::ConnectOptions::new()
+            .name(session_client_name())
+            .request_timeout(Some(timeout))
+            .connect(self.endpoint.clone())
+            .await?;
+
+        let response = client
+            .request(subject.clone(), bytes::Bytes::new())
+            .await
+            .map_err(|e| anyhow::anyhow!("Request to {} failed: {}", subject, e))?;
+
+        Ok(response.payload)
+    }
+
+    async fn publish_schedule(
+        &self,
+        subject: String,
         schedule: &Schedule,
     ) -> Result<(), anyhow::Error> {
         // let hostname = rustix::system::uname().nodename().to_string_lossy().to_string();
@@ -35,8 +305,6 @@ impl Client {
 
         println!("Connection state: {:?}", client.connection_state());
 
-        // let subject = format!("pando.schedule.{}", device_id);
-        let subject = "pando.commands.run-schedule".to_string();
         // let mut buf = vec![];
         // let containers: Vec<Container> = spec
         //     .services
@@ -93,3 +361,16 @@ impl Client {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_subjects() {
+        assert_eq!(
+            device_command_subject("0192b1c2-0000-7000-8000-000000000001", RUN_SCHEDULE),
+            "pando.devices.0192b1c2-0000-7000-8000-000000000001.commands.run-schedule"
+        );
+    }
+}
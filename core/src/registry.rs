@@ -0,0 +1,105 @@
+use bollard::auth::DockerCredentials;
+
+use crate::grpc_remote::RegistryCredential;
+
+const DOCKER_HUB: &str = "docker.io";
+
+impl std::fmt::Debug for RegistryCredential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RegistryCredential")
+            .field("registry", &self.registry)
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Folds the different ways of spelling Docker Hub into one name.
+fn normalize_registry(registry: &str) -> &str {
+    match registry {
+        "" | "docker.io" | "index.docker.io" | "registry-1.docker.io" => DOCKER_HUB,
+        other => other,
+    }
+}
+
+/// Returns the registry host an image reference would be pulled from, following the same rules
+/// as the engine: the first path component only names a registry if it looks like a host.
+pub fn registry_for_image(image: &str) -> &str {
+    match image.split_once('/') {
+        Some((first, _)) if first.contains('.') || first.contains(':') || first == "localhost" => {
+            normalize_registry(first)
+        }
+        _ => DOCKER_HUB,
+    }
+}
+
+/// Picks the credentials for an image's registry. Earlier entries win, so callers list the
+/// credentials delivered with a schedule ahead of the ones stored on the device.
+pub fn credentials_for_image<'a>(
+    image: &str,
+    credentials: impl IntoIterator<Item = &'a RegistryCredential>,
+) -> Option<DockerCredentials> {
+    let registry = registry_for_image(image);
+
+    credentials
+        .into_iter()
+        .find(|credential| normalize_registry(&credential.registry) == registry)
+        .map(|credential| DockerCredentials {
+            username: Some(credential.username.clone()),
+            password: Some(credential.password.clone()),
+            serveraddress: Some(registry.to_string()),
+            ..Default::default()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credential(registry: &str, username: &str) -> RegistryCredential {
+        RegistryCredential {
+            registry: registry.to_string(),
+            username: username.to_string(),
+            password: "hunter2".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_registry_for_image() {
+        assert_eq!(registry_for_image("nginx:latest"), "docker.io");
+        assert_eq!(registry_for_image("library/nginx"), "docker.io");
+        assert_eq!(registry_for_image("ghcr.io/uinta-labs/app:1.0"), "ghcr.io");
+        assert_eq!(registry_for_image("localhost:5000/app"), "localhost:5000");
+        assert_eq!(registry_for_image("localhost/app"), "localhost");
+        assert_eq!(
+            registry_for_image("index.docker.io/library/nginx"),
+            "docker.io"
+        );
+    }
+
+    #[test]
+    fn test_credentials_for_image() {
+        let from_schedule = [credential("ghcr.io", "schedule")];
+        let from_device = [credential("ghcr.io", "device"), credential("", "hub")];
+
+        let found = credentials_for_image(
+            "ghcr.io/uinta-labs/app",
+            from_schedule.iter().chain(from_device.iter()),
+        )
+        .unwrap();
+        assert_eq!(found.username.as_deref(), Some("schedule"));
+        assert_eq!(found.serveraddress.as_deref(), Some("ghcr.io"));
+
+        let found = credentials_for_image("nginx", from_device.iter()).unwrap();
+        assert_eq!(found.username.as_deref(), Some("hub"));
+
+        assert!(credentials_for_image("quay.io/app", from_device.iter()).is_none());
+    }
+
+    #[test]
+    fn test_debug_redacts_password() {
+        let debug = format!("{:?}", credential("ghcr.io", "someone"));
+        assert!(debug.contains("someone"));
+        assert!(!debug.contains("hunter2"));
+    }
+}
@@ -1,15 +1,49 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+
+use anyhow::bail;
+use prost::Message;
 use serde::{Deserialize, Serialize};
 
-use crate::grpc_remote::{Container, ContainerEnvironment, ContainerPortDefinition, Schedule};
+use crate::grpc_remote::{
+    Container, ContainerDevice, ContainerEnvironment, ContainerHealthcheck, ContainerNetwork,
+    ContainerPortDefinition, ContainerSysctl, ContainerUlimit, ContainerVolume, Network,
+    NetworkDriverOption, NetworkIpamPool, RegistryCredential, RestartPolicy, Schedule,
+};
+
+/// Compose-style healthcheck. Durations use the same notation as compose, e.g. `1m30s`.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Healthcheck {
+    #[serde(default, deserialize_with = "deserialize_healthcheck_test")]
+    pub test: Vec<String>,
+
+    #[serde(
+        default,
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub interval: Option<Duration>,
+
+    #[serde(
+        default,
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub timeout: Option<Duration>,
+
+    #[serde(default)]
+    pub retries: Option<u32>,
+
+    #[serde(
+        default,
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub start_period: Option<Duration>,
 
-// #[derive(Debug, Clone, Serialize, Deserialize)]
-// pub struct Healthcheck {
-//     test: Vec<String>,
-//     interval: String,
-//     timeout: String,
-//     retries: u32,
-//     start_period: String,
-// }
+    #[serde(default)]
+    pub disable: bool,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct HostFeatures {
@@ -18,6 +52,29 @@ pub struct HostFeatures {
 
     #[serde(default)]
     pub boot_partition: bool,
+
+    /// Read-only host journal
+    #[serde(default)]
+    pub journal_logs: bool,
+
+    /// Host D-Bus system bus socket
+    #[serde(default)]
+    pub dbus: bool,
+
+    /// Read-only udev database plus access to USB serial, USB, input and I2C device classes
+    #[serde(default)]
+    pub udev: bool,
+
+    /// Read-only host kernel modules
+    #[serde(default)]
+    pub kernel_modules: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UlimitSpec {
+    pub name: String,
+    pub soft: i64,
+    pub hard: i64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -38,19 +95,92 @@ pub enum VolumeHostPath {
 pub struct VolumeSpec {
     pub host_path: VolumeHostPath,
     pub container_path: String,
+    /// Set by a trailing `:ro`
+    #[serde(default)]
+    pub read_only: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Compose-style device mapping, e.g. `/dev/ttyUSB0:/dev/ttyUSB0:rw`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceSpec {
+    pub host_path: String,
+    pub container_path: String,
+    pub permissions: String,
+}
+
+/// Compose-style restart policy, e.g. `always` or `on-failure:3`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum RestartSpec {
+    #[default]
+    Unspecified,
+    No,
+    Always,
+    OnFailure(u32),
+    UnlessStopped,
+}
+
+/// Compose-style `network_mode`, e.g. `host` or `container:<service>`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum NetworkMode {
-    // Bridge(String),
-    // Container(String),
+    Bridge,
     Host,
     None,
+    /// Joins the network stack of another service in the same spec
+    Container(String),
+}
+
+/// A network a service attaches to, with the extra names it can be reached by there.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServiceNetwork {
+    pub name: String,
+    pub aliases: Vec<String>,
+}
+
+/// Compose-style entry of the top-level `networks` section.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+pub struct NetworkSpec {
+    /// Defaults to the engine's `bridge` driver
+    #[serde(default)]
+    pub driver: Option<String>,
+
+    #[serde(default, deserialize_with = "deserialize_driver_opts")]
+    pub driver_opts: BTreeMap<String, String>,
+
+    #[serde(default)]
+    pub internal: bool,
+
+    #[serde(default)]
+    pub ipam: IpamSpec,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+pub struct IpamSpec {
+    #[serde(default)]
+    pub config: Vec<IpamPoolSpec>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+pub struct IpamPoolSpec {
+    #[serde(default)]
+    pub subnet: Option<String>,
+
+    #[serde(default)]
+    pub gateway: Option<String>,
+
+    #[serde(default)]
+    pub ip_range: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Service {
     pub name: String,
+
+    /// Overrides the task id, which otherwise is the service name. Renaming a service while
+    /// keeping its id replaces its container in place rather than adding a new task and retiring
+    /// the old one; the name is part of the definition, so the container is still recreated.
+    #[serde(default)]
+    pub id: Option<String>,
+
     pub image: String,
 
     #[serde(default, deserialize_with = "deserialize_environment")]
@@ -59,11 +189,22 @@ pub struct Service {
     #[serde(default, deserialize_with = "deserialize_command")]
     pub command: Vec<String>,
 
-    #[serde(default)]
-    pub restart: String,
+    #[serde(default, deserialize_with = "deserialize_command")]
+    pub entrypoint: Vec<String>,
 
-    #[serde(default)]
-    pub networks: Vec<String>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_restart",
+        serialize_with = "serialize_restart"
+    )]
+    pub restart: RestartSpec,
+
+    /// Networks from the top-level `networks` section, or `default` for the engine's bridge
+    #[serde(default, deserialize_with = "deserialize_service_networks")]
+    pub networks: Vec<ServiceNetwork>,
+
+    #[serde(default, deserialize_with = "deserialize_network_mode")]
+    pub network_mode: Option<NetworkMode>,
 
     #[serde(default)]
     pub depends_on: Vec<String>,
@@ -77,8 +218,66 @@ pub struct Service {
     #[serde(default, deserialize_with = "deserialize_volume_specs")]
     pub volumes: Vec<VolumeSpec>,
 
-    // #[serde(default)]
-    // healthcheck: Healthcheck,
+    #[serde(default)]
+    pub healthcheck: Option<Healthcheck>,
+
+    #[serde(default)]
+    pub cap_add: Vec<String>,
+
+    #[serde(default)]
+    pub cap_drop: Vec<String>,
+
+    #[serde(default, deserialize_with = "deserialize_sysctls")]
+    pub sysctls: Vec<(String, String)>,
+
+    #[serde(
+        default,
+        deserialize_with = "deserialize_ulimits",
+        serialize_with = "serialize_ulimits"
+    )]
+    pub ulimits: Vec<UlimitSpec>,
+
+    #[serde(
+        default,
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub stop_grace_period: Option<Duration>,
+
+    #[serde(default)]
+    pub stop_signal: Option<String>,
+
+    /// Memory limit in bytes; accepts sizes such as `512m` or `1g`
+    #[serde(default, deserialize_with = "deserialize_byte_size")]
+    pub mem_limit: Option<i64>,
+
+    /// Memory plus swap limit in bytes, or -1 for unlimited swap
+    #[serde(default, deserialize_with = "deserialize_byte_size")]
+    pub memswap_limit: Option<i64>,
+
+    /// Number of CPUs the service may use, e.g. `0.5`
+    #[serde(default)]
+    pub cpus: Option<f64>,
+
+    #[serde(default)]
+    pub cpu_shares: Option<i64>,
+
+    #[serde(default)]
+    pub pids_limit: Option<i64>,
+
+    #[serde(default)]
+    pub oom_score_adj: Option<i32>,
+
+    #[serde(default)]
+    pub oom_kill_disable: bool,
+
+    #[serde(default, deserialize_with = "deserialize_device_specs")]
+    pub devices: Vec<DeviceSpec>,
+
+    /// Rules such as `c 188:* rmw`, for devices that may appear after the container starts
+    #[serde(default)]
+    pub device_cgroup_rules: Vec<String>,
+
     #[serde(default)]
     pub host_features: HostFeatures,
 }
@@ -98,17 +297,266 @@ impl std::str::FromStr for VolumeSpec {
             1 => Ok(VolumeSpec {
                 host_path: VolumeHostPath::Anonymous,
                 container_path: parts[0].to_string(),
+                read_only: false,
             }),
-            // Format: host_path:container_path
-            2 => Ok(VolumeSpec {
-                host_path: VolumeHostPath::Named(parts[0].to_string()),
-                container_path: parts[1].to_string(),
-            }),
+            // Format: host_path:container_path or host_path:container_path:mode
+            2 | 3 => {
+                let read_only = match parts.get(2) {
+                    None | Some(&"rw") => false,
+                    Some(&"ro") => true,
+                    Some(mode) => return Err(format!("Invalid volume mode '{}': {}", mode, s)),
+                };
+
+                // Named volumes can't contain a slash, and there is no project directory on the
+                // device for a relative path to be relative to
+                let source = parts[0];
+                if !source.starts_with('/')
+                    && (source.contains('/') || source.starts_with('.') || source.starts_with('~'))
+                {
+                    return Err(format!(
+                        "Relative bind mounts aren't supported, use an absolute path: {}",
+                        s
+                    ));
+                }
+
+                Ok(VolumeSpec {
+                    host_path: VolumeHostPath::Named(source.to_string()),
+                    container_path: parts[1].to_string(),
+                    read_only,
+                })
+            }
             _ => Err(format!("Invalid volume specification: {}", s)),
         }
     }
 }
 
+fn is_cgroup_permissions(s: &str) -> bool {
+    !s.is_empty() && s.len() <= 3 && s.chars().all(|c| "rwm".contains(c))
+}
+
+impl std::str::FromStr for DeviceSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+
+        let (host_path, container_path, permissions) = match parts.as_slice() {
+            // Format: host_path
+            [host_path] => (*host_path, *host_path, "rwm"),
+            // Format: host_path:permissions
+            [host_path, permissions] if is_cgroup_permissions(permissions) => {
+                (*host_path, *host_path, *permissions)
+            }
+            // Format: host_path:container_path
+            [host_path, container_path] => (*host_path, *container_path, "rwm"),
+            // Format: host_path:container_path:permissions
+            [host_path, container_path, permissions] if is_cgroup_permissions(permissions) => {
+                (*host_path, *container_path, *permissions)
+            }
+            _ => return Err(format!("Invalid device specification: {}", s)),
+        };
+
+        if !host_path.starts_with('/') || !container_path.starts_with('/') {
+            return Err(format!("Device paths must be absolute: {}", s));
+        }
+
+        Ok(DeviceSpec {
+            host_path: host_path.to_string(),
+            container_path: container_path.to_string(),
+            permissions: permissions.to_string(),
+        })
+    }
+}
+
+/// Checks a device cgroup rule has the `<type> <major>:<minor> <permissions>` form the engine
+/// expects, e.g. `c 189:* rwm`.
+fn validate_device_cgroup_rule(rule: &str) -> Result<(), String> {
+    let is_number = |s: &str| s == "*" || (!s.is_empty() && s.chars().all(|c| c.is_ascii_digit()));
+
+    let valid = match rule.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [kind, numbers, permissions] => {
+            matches!(*kind, "a" | "b" | "c")
+                && numbers
+                    .split_once(':')
+                    .is_some_and(|(major, minor)| is_number(major) && is_number(minor))
+                && is_cgroup_permissions(permissions)
+        }
+        _ => false,
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid device cgroup rule: {}", rule))
+    }
+}
+
+impl std::str::FromStr for RestartSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("on-failure", retries)) => retries
+                .parse::<u32>()
+                .map(RestartSpec::OnFailure)
+                .map_err(|_| format!("Invalid restart retry count: {}", retries)),
+            Some(_) => Err(format!("Invalid restart policy: {}", s)),
+            None => match s {
+                "" => Ok(RestartSpec::Unspecified),
+                "no" => Ok(RestartSpec::No),
+                "always" => Ok(RestartSpec::Always),
+                "on-failure" => Ok(RestartSpec::OnFailure(0)),
+                "unless-stopped" => Ok(RestartSpec::UnlessStopped),
+                _ => Err(format!("Invalid restart policy: {}", s)),
+            },
+        }
+    }
+}
+
+impl std::str::FromStr for NetworkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("container", service)) if !service.is_empty() => {
+                Ok(NetworkMode::Container(service.to_string()))
+            }
+            Some(_) => Err(format!("Invalid network mode: {}", s)),
+            None => match s {
+                "bridge" => Ok(NetworkMode::Bridge),
+                "host" => Ok(NetworkMode::Host),
+                "none" => Ok(NetworkMode::None),
+                _ => Err(format!("Invalid network mode: {}", s)),
+            },
+        }
+    }
+}
+
+impl NetworkMode {
+    fn to_proto(&self) -> String {
+        match self {
+            NetworkMode::Bridge => "bridge".to_string(),
+            NetworkMode::Host => "host".to_string(),
+            NetworkMode::None => "none".to_string(),
+            NetworkMode::Container(service) => format!("container:{}", service),
+        }
+    }
+}
+
+impl std::fmt::Display for RestartSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RestartSpec::Unspecified => Ok(()),
+            RestartSpec::No => write!(f, "no"),
+            RestartSpec::Always => write!(f, "always"),
+            RestartSpec::OnFailure(0) => write!(f, "on-failure"),
+            RestartSpec::OnFailure(retries) => write!(f, "on-failure:{}", retries),
+            RestartSpec::UnlessStopped => write!(f, "unless-stopped"),
+        }
+    }
+}
+
+impl RestartSpec {
+    fn to_proto(&self) -> (RestartPolicy, i32) {
+        match self {
+            RestartSpec::Unspecified => (RestartPolicy::Unspecified, 0),
+            RestartSpec::No => (RestartPolicy::No, 0),
+            RestartSpec::Always => (RestartPolicy::Always, 0),
+            RestartSpec::OnFailure(retries) => (RestartPolicy::OnFailure, *retries as i32),
+            RestartSpec::UnlessStopped => (RestartPolicy::UnlessStopped, 0),
+        }
+    }
+
+    fn from_proto(policy: RestartPolicy, max_retries: i32) -> Self {
+        match policy {
+            RestartPolicy::Unspecified => RestartSpec::Unspecified,
+            RestartPolicy::No => RestartSpec::No,
+            RestartPolicy::Always => RestartSpec::Always,
+            RestartPolicy::OnFailure => RestartSpec::OnFailure(max_retries.max(0) as u32),
+            RestartPolicy::UnlessStopped => RestartSpec::UnlessStopped,
+        }
+    }
+}
+
+impl Healthcheck {
+    fn to_proto(&self) -> ContainerHealthcheck {
+        let millis =
+            |duration: Option<Duration>| duration.map(|d| d.as_millis() as i64).unwrap_or_default();
+
+        ContainerHealthcheck {
+            test: if self.disable {
+                vec!["NONE".to_string()]
+            } else {
+                self.test.clone()
+            },
+            interval_ms: millis(self.interval),
+            timeout_ms: millis(self.timeout),
+            retries: self.retries.unwrap_or_default() as i32,
+            start_period_ms: millis(self.start_period),
+        }
+    }
+
+    fn from_proto(healthcheck: &ContainerHealthcheck) -> Self {
+        let duration = |millis: i64| {
+            if millis > 0 {
+                Some(Duration::from_millis(millis as u64))
+            } else {
+                None
+            }
+        };
+
+        Healthcheck {
+            disable: healthcheck.test.first().is_some_and(|test| test == "NONE"),
+            test: healthcheck.test.clone(),
+            interval: duration(healthcheck.interval_ms),
+            timeout: duration(healthcheck.timeout_ms),
+            retries: if healthcheck.retries > 0 {
+                Some(healthcheck.retries as u32)
+            } else {
+                None
+            },
+            start_period: duration(healthcheck.start_period_ms),
+        }
+    }
+}
+
+/// Parses durations such as `30s`, `1m30s` or `500ms`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration: {}", s);
+
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+
+    let mut total = Duration::ZERO;
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .ok_or_else(invalid)?;
+        let unit_len = rest[number_len..]
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len() - number_len);
+
+        let value = rest[..number_len].parse::<f64>().map_err(|_| invalid())?;
+        let unit_seconds = match &rest[number_len..number_len + unit_len] {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" => 1.0,
+            "ms" => 1e-3,
+            "us" | "µs" => 1e-6,
+            "ns" => 1e-9,
+            _ => return Err(invalid()),
+        };
+
+        let duration = Duration::try_from_secs_f64(value * unit_seconds).map_err(|_| invalid())?;
+        total = total.checked_add(duration).ok_or_else(invalid)?;
+        rest = &rest[number_len + unit_len..];
+    }
+
+    Ok(total)
+}
+
 impl std::str::FromStr for PortSpec {
     type Err = String;
 
@@ -225,131 +673,754 @@ where
         .collect()
 }
 
-fn deserialize_environment<'de, D>(deserializer: D) -> Result<Vec<(String, String)>, D::Error>
+fn deserialize_device_specs<'de, D>(deserializer: D) -> Result<Vec<DeviceSpec>, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
-    let env_vars = Vec::<String>::deserialize(deserializer)?;
-
-    env_vars
+    Vec::<String>::deserialize(deserializer)?
         .into_iter()
-        .map(|var| match var {
-            s if s.contains('=') => {
-                let parts: Vec<&str> = s.split('=').collect();
-                if parts.len() == 2 {
-                    Ok((parts[0].to_string(), parts[1].to_string()))
-                } else {
-                    Err(serde::de::Error::custom(format!(
-                        "Invalid environment variable format: {}",
-                        s
-                    )))
-                }
-            }
-            _ => Ok((var.clone(), "".to_string())),
-        })
+        .map(|s| s.parse().map_err(serde::de::Error::custom))
         .collect()
 }
 
-fn deserialize_command<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+fn deserialize_restart<'de, D>(deserializer: D) -> Result<RestartSpec, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
+    // YAML reads a bare `no` as a boolean, so accept that too
     #[derive(Deserialize)]
     #[serde(untagged)]
-    enum Command {
+    enum Restart {
+        AsBool(bool),
         AsString(String),
-        AsArray(Vec<String>),
     }
 
-    let command = Command::deserialize(deserializer)?;
-
-    match command {
-        Command::AsString(s) => Ok(vec![s]),
-        Command::AsArray(arr) => Ok(arr),
+    match Restart::deserialize(deserializer)? {
+        Restart::AsBool(false) => Ok(RestartSpec::No),
+        Restart::AsBool(true) => Err(serde::de::Error::custom("Invalid restart policy: true")),
+        Restart::AsString(s) => s.parse().map_err(serde::de::Error::custom),
     }
 }
 
-/// The Spec struct represents the specification as defined in the YAML file.
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Spec {
-    pub version: String,
-    pub services: Vec<Service>,
+fn serialize_restart<S>(restart: &RestartSpec, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.collect_str(restart)
 }
 
-impl Spec {
-    pub fn read_from(path: &str) -> Result<Self, anyhow::Error> {
-        let file = std::fs::File::open(path)?;
-        let reader = std::io::BufReader::new(file);
-        let spec = serde_yaml::from_reader(reader)?;
-        Ok(spec)
+fn deserialize_network_mode<'de, D>(deserializer: D) -> Result<Option<NetworkMode>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let network_mode = Option::<String>::deserialize(deserializer)?;
+
+    network_mode
+        .map(|s| s.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+fn deserialize_service_networks<'de, D>(deserializer: D) -> Result<Vec<ServiceNetwork>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize, Default)]
+    struct Attachment {
+        #[serde(default)]
+        aliases: Vec<String>,
     }
 
-    pub fn from_schedule(schedule: &Schedule) -> anyhow::Result<Self> {
-        Ok(Spec {
-            version: "0.0.1".to_string(),
-            services: schedule
-                .containers
-                .iter()
-                .map(|container| Service {
-                    name: container.name.clone(),
-                    command: container.command.clone(),
-                    image: container.container_image.clone(),
-                    environment: container
-                        .environment
-                        .iter()
-                        .map(|env| (env.key.clone(), env.value.clone()))
-                        .collect(),
-                    privileged: container.privileged,
-                    restart: "always".to_string(),
-                    host_features: HostFeatures {
-                        daemon_socket: container.bind_docker_socket,
-                        boot_partition: container.bind_boot,
-                    },
-                    ports: container
-                        .ports
-                        .iter()
-                        .map(|port| PortSpec {
-                            host_ip: Some(port.host_ip.clone()),
-                            host_port: port.host_port as u16,
-                            container_port: port.container_port as u16,
-                            protocol: port.protocol.clone(),
-                        })
-                        .collect(),
-                    networks: vec![],
-                    depends_on: vec![],
-                    volumes: vec![],
+    // A YAML mapping keeps its keys in the order written, which decides the network the
+    // container is created on
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Networks {
+        AsList(Vec<String>),
+        AsMap(serde_yaml::Mapping),
+    }
+
+    match Networks::deserialize(deserializer)? {
+        Networks::AsList(list) => Ok(list
+            .into_iter()
+            .map(|name| ServiceNetwork {
+                name,
+                aliases: vec![],
+            })
+            .collect()),
+        Networks::AsMap(map) => map
+            .into_iter()
+            .map(|(name, attachment)| {
+                let name = match name {
+                    serde_yaml::Value::String(name) => name,
+                    _ => return Err(serde::de::Error::custom("Network names must be strings")),
+                };
+                let attachment: Option<Attachment> =
+                    serde_yaml::from_value(attachment).map_err(serde::de::Error::custom)?;
+                Ok(ServiceNetwork {
+                    name,
+                    aliases: attachment.unwrap_or_default().aliases,
                 })
-                .collect(),
-        })
+            })
+            .collect(),
     }
 }
 
-impl Schedule {
-    pub fn from_spec(spec: &Spec) -> Self {
-        let mut schedule = Schedule::default();
+fn deserialize_network_specs<'de, D>(
+    deserializer: D,
+) -> Result<BTreeMap<String, NetworkSpec>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    // A network with nothing to configure is written as a bare `name:`
+    let networks = BTreeMap::<String, Option<NetworkSpec>>::deserialize(deserializer)?;
 
-        for service in &spec.services {
-            schedule.containers.push(Container {
-                id: uuid::Uuid::now_v7().to_string(),
-                entrypoint: "".to_string(), // not yet supported on spec side
+    Ok(networks
+        .into_iter()
+        .map(|(name, network)| (name, network.unwrap_or_default()))
+        .collect())
+}
 
-                name: service.name.clone(),
-                container_image: service.image.clone(),
-                command: service.command.clone(),
-                environment: service
-                    .environment
-                    .iter()
-                    .map(|(k, v)| ContainerEnvironment {
-                        key: k.clone(),
-                        value: v.clone(),
-                    })
-                    .collect(),
-                privileged: service.privileged,
-                bind_boot: service.host_features.boot_partition,
-                bind_docker_socket: service.host_features.daemon_socket,
-                ports: service
-                    .ports
-                    .iter()
+fn deserialize_driver_opts<'de, D>(deserializer: D) -> Result<BTreeMap<String, String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    BTreeMap::<String, serde_yaml::Value>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(key, value)| match value {
+            serde_yaml::Value::String(s) => Ok((key, s)),
+            serde_yaml::Value::Number(n) => Ok((key, n.to_string())),
+            serde_yaml::Value::Bool(b) => Ok((key, b.to_string())),
+            _ => Err(serde::de::Error::custom(format!(
+                "Invalid value for driver option {}",
+                key
+            ))),
+        })
+        .collect()
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let duration = Option::<String>::deserialize(deserializer)?;
+
+    duration
+        .map(|s| parse_duration(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Writes durations in the notation `parse_duration` reads, using the coarsest exact unit.
+fn serialize_duration<S>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match duration {
+        None => serializer.serialize_none(),
+        Some(duration) if duration.subsec_nanos() == 0 => {
+            serializer.serialize_some(&format!("{}s", duration.as_secs()))
+        }
+        Some(duration) if duration.subsec_nanos() % 1_000_000 == 0 => {
+            serializer.serialize_some(&format!("{}ms", duration.as_millis()))
+        }
+        Some(duration) => serializer.serialize_some(&format!("{}ns", duration.as_nanos())),
+    }
+}
+
+/// Parses byte sizes such as `1024`, `512k`, `256m` or `1.5g`, as accepted by compose.
+fn parse_byte_size(s: &str) -> Result<i64, String> {
+    let invalid = || format!("Invalid size: {}", s);
+
+    let s = s.trim().to_ascii_lowercase();
+    let number_len = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+
+    let value = s[..number_len].parse::<f64>().map_err(|_| invalid())?;
+    let multiplier = match &s[number_len..] {
+        "" | "b" => 1.0,
+        "k" | "kb" => 1024.0,
+        "m" | "mb" => 1024.0 * 1024.0,
+        "g" | "gb" => 1024.0 * 1024.0 * 1024.0,
+        _ => return Err(invalid()),
+    };
+
+    Ok((value * multiplier) as i64)
+}
+
+fn deserialize_byte_size<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        AsNumber(i64),
+        AsString(String),
+    }
+
+    match Option::<Size>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Size::AsNumber(bytes)) => Ok(Some(bytes)),
+        Some(Size::AsString(s)) => parse_byte_size(&s)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+fn deserialize_healthcheck_test<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Test {
+        AsString(String),
+        AsArray(Vec<String>),
+    }
+
+    // Like compose, a plain string is run through the container's shell
+    match Test::deserialize(deserializer)? {
+        Test::AsString(s) => Ok(vec!["CMD-SHELL".to_string(), s]),
+        Test::AsArray(arr) => Ok(arr),
+    }
+}
+
+fn deserialize_sysctls<'de, D>(deserializer: D) -> Result<Vec<(String, String)>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Sysctls {
+        AsMap(BTreeMap<String, serde_yaml::Value>),
+        AsList(Vec<String>),
+    }
+
+    match Sysctls::deserialize(deserializer)? {
+        Sysctls::AsMap(map) => map
+            .into_iter()
+            .map(|(key, value)| match value {
+                serde_yaml::Value::String(s) => Ok((key, s)),
+                serde_yaml::Value::Number(n) => Ok((key, n.to_string())),
+                serde_yaml::Value::Bool(b) => Ok((key, b.to_string())),
+                _ => Err(serde::de::Error::custom(format!(
+                    "Invalid value for sysctl {}",
+                    key
+                ))),
+            })
+            .collect(),
+        Sysctls::AsList(list) => list
+            .into_iter()
+            .map(|sysctl| match sysctl.split_once('=') {
+                Some((key, value)) => Ok((key.to_string(), value.to_string())),
+                None => Err(serde::de::Error::custom(format!(
+                    "Invalid sysctl format: {}",
+                    sysctl
+                ))),
+            })
+            .collect(),
+    }
+}
+
+fn deserialize_ulimits<'de, D>(deserializer: D) -> Result<Vec<UlimitSpec>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Ulimit {
+        AsSingle(i64),
+        AsPair { soft: i64, hard: i64 },
+    }
+
+    let ulimits = BTreeMap::<String, Ulimit>::deserialize(deserializer)?;
+
+    ulimits
+        .into_iter()
+        .map(|(name, ulimit)| {
+            let (soft, hard) = match ulimit {
+                Ulimit::AsSingle(limit) => (limit, limit),
+                Ulimit::AsPair { soft, hard } => (soft, hard),
+            };
+            if soft > hard {
+                return Err(serde::de::Error::custom(format!(
+                    "Soft limit for ulimit {} is above its hard limit",
+                    name
+                )));
+            }
+            Ok(UlimitSpec { name, soft, hard })
+        })
+        .collect()
+}
+
+fn serialize_ulimits<S>(ulimits: &[UlimitSpec], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    #[derive(Serialize)]
+    struct Ulimit {
+        soft: i64,
+        hard: i64,
+    }
+
+    serializer.collect_map(ulimits.iter().map(|ulimit| {
+        (
+            &ulimit.name,
+            Ulimit {
+                soft: ulimit.soft,
+                hard: ulimit.hard,
+            },
+        )
+    }))
+}
+
+fn deserialize_environment<'de, D>(deserializer: D) -> Result<Vec<(String, String)>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let env_vars = Vec::<String>::deserialize(deserializer)?;
+
+    env_vars
+        .into_iter()
+        .map(|var| match var {
+            s if s.contains('=') => {
+                let parts: Vec<&str> = s.split('=').collect();
+                if parts.len() == 2 {
+                    Ok((parts[0].to_string(), parts[1].to_string()))
+                } else {
+                    Err(serde::de::Error::custom(format!(
+                        "Invalid environment variable format: {}",
+                        s
+                    )))
+                }
+            }
+            _ => Ok((var.clone(), "".to_string())),
+        })
+        .collect()
+}
+
+fn deserialize_command<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Command {
+        AsString(String),
+        AsArray(Vec<String>),
+    }
+
+    let command = Command::deserialize(deserializer)?;
+
+    match command {
+        Command::AsString(s) => Ok(vec![s]),
+        Command::AsArray(arr) => Ok(arr),
+    }
+}
+
+/// The Spec struct represents the specification as defined in the YAML file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Spec {
+    pub version: String,
+    pub services: Vec<Service>,
+
+    /// Networks the agent creates for the services, keyed by name
+    #[serde(default, deserialize_with = "deserialize_network_specs")]
+    pub networks: BTreeMap<String, NetworkSpec>,
+
+    /// Delivered to the device along with the schedule and used for image pulls.
+    #[serde(default)]
+    pub registry_credentials: Vec<RegistryCredential>,
+}
+
+impl Spec {
+    pub fn read_from(path: &str) -> Result<Self, anyhow::Error> {
+        let file = std::fs::File::open(path)?;
+        let reader = std::io::BufReader::new(file);
+        let spec: Spec = serde_yaml::from_reader(reader)?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    /// Checks the parts of a spec that serde can't, such as references between services.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        let schedule = Schedule::from_spec(self);
+
+        let mut ids = HashSet::new();
+        for container in &schedule.containers {
+            if !ids.insert(container.id.as_str()) {
+                bail!("Duplicate service id '{}'", container.id);
+            }
+        }
+
+        for service in &self.services {
+            service.validate_resources()?;
+
+            for rule in &service.device_cgroup_rules {
+                validate_device_cgroup_rule(rule)
+                    .map_err(|e| anyhow::anyhow!("Service '{}': {}", service.name, e))?;
+            }
+        }
+
+        schedule.validate_networks()?;
+        schedule.start_order()?;
+        Ok(())
+    }
+
+    pub fn from_schedule(schedule: &Schedule) -> anyhow::Result<Self> {
+        let positive = |value: i64| if value > 0 { Some(value) } else { None };
+
+        Ok(Spec {
+            version: "0.0.1".to_string(),
+            registry_credentials: schedule.registry_credentials.clone(),
+            networks: schedule
+                .networks
+                .iter()
+                .map(|network| {
+                    let spec = NetworkSpec {
+                        driver: if network.driver.is_empty() {
+                            None
+                        } else {
+                            Some(network.driver.clone())
+                        },
+                        driver_opts: network
+                            .driver_opts
+                            .iter()
+                            .map(|opt| (opt.key.clone(), opt.value.clone()))
+                            .collect(),
+                        internal: network.internal,
+                        ipam: IpamSpec {
+                            config: network
+                                .ipam_pools
+                                .iter()
+                                .map(|pool| IpamPoolSpec {
+                                    subnet: Some(pool.subnet.clone()).filter(|s| !s.is_empty()),
+                                    gateway: Some(pool.gateway.clone()).filter(|s| !s.is_empty()),
+                                    ip_range: Some(pool.ip_range.clone()).filter(|s| !s.is_empty()),
+                                })
+                                .collect(),
+                        },
+                    };
+                    (network.name.clone(), spec)
+                })
+                .collect(),
+            services: schedule
+                .containers
+                .iter()
+                .map(|container| {
+                    Ok(Service {
+                        name: container.name.clone(),
+                        id: if container.id == container.name {
+                            None
+                        } else {
+                            Some(container.id.clone())
+                        },
+                        command: container.command.clone(),
+                        entrypoint: container.entrypoint_command(),
+                        image: container.container_image.clone(),
+                        environment: container
+                            .environment
+                            .iter()
+                            .map(|env| (env.key.clone(), env.value.clone()))
+                            .collect(),
+                        privileged: container.privileged,
+                        restart: RestartSpec::from_proto(
+                            container.restart_policy(),
+                            container.restart_max_retries,
+                        ),
+                        host_features: HostFeatures {
+                            daemon_socket: container.bind_docker_socket,
+                            boot_partition: container.bind_boot,
+                            journal_logs: container.bind_journal_logs,
+                            dbus: container.bind_dbus,
+                            udev: container.bind_udev,
+                            kernel_modules: container.bind_kernel_modules,
+                        },
+                        ports: container
+                            .ports
+                            .iter()
+                            .map(|port| PortSpec {
+                                host_ip: Some(port.host_ip.clone()),
+                                host_port: port.host_port as u16,
+                                container_port: port.container_port as u16,
+                                protocol: port.protocol.clone(),
+                            })
+                            .collect(),
+                        networks: container
+                            .networks
+                            .iter()
+                            .map(|network| ServiceNetwork {
+                                name: network.name.clone(),
+                                aliases: network.aliases.clone(),
+                            })
+                            .collect(),
+                        network_mode: match container.network_mode.as_str() {
+                            "" | "bridge" => None,
+                            network_mode => Some(network_mode.parse().map_err(anyhow::Error::msg)?),
+                        },
+                        depends_on: container.depends_on.clone(),
+                        healthcheck: container.healthcheck.as_ref().map(Healthcheck::from_proto),
+                        cap_add: container.cap_add.clone(),
+                        cap_drop: container.cap_drop.clone(),
+                        sysctls: container
+                            .sysctls
+                            .iter()
+                            .map(|sysctl| (sysctl.key.clone(), sysctl.value.clone()))
+                            .collect(),
+                        ulimits: container
+                            .ulimits
+                            .iter()
+                            .map(|ulimit| UlimitSpec {
+                                name: ulimit.name.clone(),
+                                soft: ulimit.soft,
+                                hard: ulimit.hard,
+                            })
+                            .collect(),
+                        volumes: container
+                            .volumes
+                            .iter()
+                            .map(|volume| VolumeSpec {
+                                host_path: if volume.source.is_empty() {
+                                    VolumeHostPath::Anonymous
+                                } else {
+                                    VolumeHostPath::Named(volume.source.clone())
+                                },
+                                container_path: volume.container_path.clone(),
+                                read_only: volume.read_only,
+                            })
+                            .collect(),
+                        stop_grace_period: if container.stop_grace_period_ms > 0 {
+                            Some(Duration::from_millis(container.stop_grace_period_ms as u64))
+                        } else {
+                            None
+                        },
+                        stop_signal: if container.stop_signal.is_empty() {
+                            None
+                        } else {
+                            Some(container.stop_signal.clone())
+                        },
+                        mem_limit: positive(container.mem_limit_bytes),
+                        memswap_limit: match container.memswap_limit_bytes {
+                            0 => None,
+                            memswap_limit => Some(memswap_limit),
+                        },
+                        cpus: if container.nano_cpus > 0 {
+                            Some(container.nano_cpus as f64 / 1e9)
+                        } else {
+                            None
+                        },
+                        cpu_shares: positive(container.cpu_shares),
+                        pids_limit: match container.pids_limit {
+                            0 => None,
+                            pids_limit => Some(pids_limit),
+                        },
+                        oom_score_adj: match container.oom_score_adj {
+                            0 => None,
+                            oom_score_adj => Some(oom_score_adj),
+                        },
+                        oom_kill_disable: container.oom_kill_disable,
+                        devices: container
+                            .devices
+                            .iter()
+                            .map(|device| DeviceSpec {
+                                host_path: device.host_path.clone(),
+                                container_path: device.container_path.clone(),
+                                permissions: device.cgroup_permissions.clone(),
+                            })
+                            .collect(),
+                        device_cgroup_rules: container.device_cgroup_rules.clone(),
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+}
+
+impl Service {
+    /// Rejects limits the engine would refuse, before they ever reach a device.
+    fn validate_resources(&self) -> Result<(), anyhow::Error> {
+        // The engine refuses anything smaller
+        const MIN_MEMORY_LIMIT: i64 = 6 * 1024 * 1024;
+
+        if let Some(mem_limit) = self.mem_limit {
+            if mem_limit < MIN_MEMORY_LIMIT {
+                bail!("Service '{}': mem_limit must be at least 6m", self.name);
+            }
+        }
+
+        if let Some(memswap_limit) = self.memswap_limit {
+            match self.mem_limit {
+                None => bail!(
+                    "Service '{}': memswap_limit requires mem_limit to be set",
+                    self.name
+                ),
+                Some(mem_limit) if memswap_limit != -1 && memswap_limit < mem_limit => bail!(
+                    "Service '{}': memswap_limit must be at least mem_limit, or -1",
+                    self.name
+                ),
+                Some(_) => {}
+            }
+        }
+
+        if let Some(cpus) = self.cpus {
+            // The engine rejects anything under a hundredth of a CPU
+            if !(cpus >= 0.01 && cpus.is_finite()) {
+                bail!("Service '{}': cpus must be at least 0.01", self.name);
+            }
+        }
+
+        if let Some(cpu_shares) = self.cpu_shares {
+            if !(2..=262144).contains(&cpu_shares) {
+                bail!(
+                    "Service '{}': cpu_shares must be between 2 and 262144",
+                    self.name
+                );
+            }
+        }
+
+        if let Some(pids_limit) = self.pids_limit {
+            if pids_limit == 0 || pids_limit < -1 {
+                bail!(
+                    "Service '{}': pids_limit must be positive, or -1",
+                    self.name
+                );
+            }
+        }
+
+        if let Some(oom_score_adj) = self.oom_score_adj {
+            if !(-1000..=1000).contains(&oom_score_adj) {
+                bail!(
+                    "Service '{}': oom_score_adj must be between -1000 and 1000",
+                    self.name
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+
+/// FNV-1a, continuing from `hash`
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+impl Container {
+    /// A digest of the entire container definition, stable across agents and releases so it can
+    /// be compared against the one stored on a running container.
+    pub fn definition_hash(&self) -> String {
+        // Over the protobuf encoding, which prost emits in field order
+        format!("{:016x}", fnv1a(FNV_OFFSET_BASIS, &self.encode_to_vec()))
+    }
+
+    /// The name of the container whose network stack this one joins, if any.
+    pub fn network_container(&self) -> Option<&str> {
+        self.network_mode.strip_prefix("container:")
+    }
+
+    /// The entrypoint to run, from `entrypoint_args` or else the older single-string field.
+    pub fn entrypoint_command(&self) -> Vec<String> {
+        if self.entrypoint_args.is_empty() && !self.entrypoint.is_empty() {
+            vec![self.entrypoint.clone()]
+        } else {
+            self.entrypoint_args.clone()
+        }
+    }
+
+    /// Names of the containers that must be started before this one.
+    pub fn dependencies(&self) -> impl Iterator<Item = &str> {
+        self.depends_on
+            .iter()
+            .map(String::as_str)
+            .chain(self.network_container())
+    }
+}
+
+impl Network {
+    /// A digest of the network definition, compared against the one stored on the network.
+    pub fn definition_hash(&self) -> String {
+        format!("{:016x}", fnv1a(FNV_OFFSET_BASIS, &self.encode_to_vec()))
+    }
+}
+
+impl Schedule {
+    pub fn from_spec(spec: &Spec) -> Self {
+        let mut schedule = Schedule {
+            registry_credentials: spec.registry_credentials.clone(),
+            networks: spec
+                .networks
+                .iter()
+                .map(|(name, network)| Network {
+                    name: name.clone(),
+                    driver: network.driver.clone().unwrap_or_default(),
+                    driver_opts: network
+                        .driver_opts
+                        .iter()
+                        .map(|(key, value)| NetworkDriverOption {
+                            key: key.clone(),
+                            value: value.clone(),
+                        })
+                        .collect(),
+                    internal: network.internal,
+                    ipam_pools: network
+                        .ipam
+                        .config
+                        .iter()
+                        .map(|pool| NetworkIpamPool {
+                            subnet: pool.subnet.clone().unwrap_or_default(),
+                            gateway: pool.gateway.clone().unwrap_or_default(),
+                            ip_range: pool.ip_range.clone().unwrap_or_default(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        for service in &spec.services {
+            let (restart_policy, restart_max_retries) = service.restart.to_proto();
+
+            // Older specs asked for host networking by listing it as the only network
+            let legacy_network_mode = match service.networks.as_slice() {
+                [network] if network.aliases.is_empty() => match network.name.as_str() {
+                    "host" => Some(NetworkMode::Host),
+                    "none" => Some(NetworkMode::None),
+                    _ => None,
+                },
+                _ => None,
+            };
+
+            schedule.containers.push(Container {
+                id: service.id.clone().unwrap_or_else(|| service.name.clone()),
+                name: service.name.clone(),
+                container_image: service.image.clone(),
+                command: service.command.clone(),
+                entrypoint: String::new(),
+                entrypoint_args: service.entrypoint.clone(),
+                environment: service
+                    .environment
+                    .iter()
+                    .map(|(k, v)| ContainerEnvironment {
+                        key: k.clone(),
+                        value: v.clone(),
+                    })
+                    .collect(),
+                privileged: service.privileged,
+                bind_boot: service.host_features.boot_partition,
+                bind_docker_socket: service.host_features.daemon_socket,
+                bind_journal_logs: service.host_features.journal_logs,
+                bind_dbus: service.host_features.dbus,
+                bind_udev: service.host_features.udev,
+                bind_kernel_modules: service.host_features.kernel_modules,
+                ports: service
+                    .ports
+                    .iter()
                     .map(|port| ContainerPortDefinition {
                         container_port: port.container_port as i32,
                         host_ip: port.host_ip.clone().unwrap_or("".to_string()),
@@ -357,20 +1428,248 @@ impl Schedule {
                         protocol: port.protocol.clone(),
                     })
                     .collect(),
-                network_mode: match service.networks.first() {
-                    Some(network) => network.clone(),
-                    None => "bridge".to_string(),
+                volumes: service
+                    .volumes
+                    .iter()
+                    .map(|volume| ContainerVolume {
+                        source: match &volume.host_path {
+                            VolumeHostPath::Named(name) => name.clone(),
+                            VolumeHostPath::Anonymous => "".to_string(),
+                        },
+                        container_path: volume.container_path.clone(),
+                        read_only: volume.read_only,
+                    })
+                    .collect(),
+                depends_on: service.depends_on.clone(),
+                healthcheck: service.healthcheck.as_ref().map(Healthcheck::to_proto),
+                cap_add: service.cap_add.clone(),
+                cap_drop: service.cap_drop.clone(),
+                sysctls: service
+                    .sysctls
+                    .iter()
+                    .map(|(key, value)| ContainerSysctl {
+                        key: key.clone(),
+                        value: value.clone(),
+                    })
+                    .collect(),
+                ulimits: service
+                    .ulimits
+                    .iter()
+                    .map(|ulimit| ContainerUlimit {
+                        name: ulimit.name.clone(),
+                        soft: ulimit.soft,
+                        hard: ulimit.hard,
+                    })
+                    .collect(),
+                stop_grace_period_ms: service
+                    .stop_grace_period
+                    .map(|d| d.as_millis() as i64)
+                    .unwrap_or_default(),
+                stop_signal: service.stop_signal.clone().unwrap_or_default(),
+                mem_limit_bytes: service.mem_limit.unwrap_or_default(),
+                memswap_limit_bytes: service.memswap_limit.unwrap_or_default(),
+                nano_cpus: service
+                    .cpus
+                    .map(|cpus| (cpus * 1e9).round() as i64)
+                    .unwrap_or_default(),
+                cpu_shares: service.cpu_shares.unwrap_or_default(),
+                pids_limit: service.pids_limit.unwrap_or_default(),
+                oom_score_adj: service.oom_score_adj.unwrap_or_default(),
+                oom_kill_disable: service.oom_kill_disable,
+                devices: service
+                    .devices
+                    .iter()
+                    .map(|device| ContainerDevice {
+                        host_path: device.host_path.clone(),
+                        container_path: device.container_path.clone(),
+                        cgroup_permissions: device.permissions.clone(),
+                    })
+                    .collect(),
+                device_cgroup_rules: service.device_cgroup_rules.clone(),
+                restart_policy: restart_policy.into(),
+                restart_max_retries,
+                network_mode: legacy_network_mode
+                    .as_ref()
+                    .or(service.network_mode.as_ref())
+                    .unwrap_or(&NetworkMode::Bridge)
+                    .to_proto(),
+                networks: if legacy_network_mode.is_some() {
+                    vec![]
+                } else {
+                    service
+                        .networks
+                        .iter()
+                        .map(|network| ContainerNetwork {
+                            name: network.name.clone(),
+                            aliases: network.aliases.clone(),
+                        })
+                        .collect()
                 },
             });
         }
 
-        schedule
+        schedule
+    }
+
+    /// Like `Container::definition_hash`, but also covers the networks the container attaches
+    /// to, so that redefining a network recreates the containers on it.
+    pub fn container_hash(&self, container: &Container) -> String {
+        if container.networks.is_empty() {
+            return container.definition_hash();
+        }
+
+        let mut hash = fnv1a(FNV_OFFSET_BASIS, &container.encode_to_vec());
+        for attachment in &container.networks {
+            if let Some(network) = self.networks.iter().find(|n| n.name == attachment.name) {
+                hash = fnv1a(hash, &network.encode_to_vec());
+            }
+        }
+        format!("{:016x}", hash)
+    }
+
+    /// Checks that network names are usable and every network a container refers to exists.
+    fn validate_networks(&self) -> Result<(), anyhow::Error> {
+        let mut names = HashSet::new();
+        for network in &self.networks {
+            if matches!(
+                network.name.as_str(),
+                "default" | "bridge" | "host" | "none"
+            ) {
+                bail!("Network name '{}' is reserved", network.name);
+            }
+            if !names.insert(network.name.as_str()) {
+                bail!("Duplicate network '{}'", network.name);
+            }
+        }
+
+        for container in &self.containers {
+            if !container.networks.is_empty() && container.network_mode != "bridge" {
+                bail!(
+                    "Container '{}' can't combine networks with network_mode '{}'",
+                    container.name,
+                    container.network_mode
+                );
+            }
+
+            for network in &container.networks {
+                if network.name == "default" {
+                    if !network.aliases.is_empty() {
+                        bail!(
+                            "Container '{}': aliases aren't supported on the default network",
+                            container.name
+                        );
+                    }
+                } else if !names.contains(network.name.as_str()) {
+                    bail!(
+                        "Container '{}' uses undefined network '{}'",
+                        container.name,
+                        network.name
+                    );
+                }
+            }
+
+            if let Some(target) = container.network_container() {
+                if target == container.name {
+                    bail!(
+                        "Container '{}' can't share its own network stack",
+                        container.name
+                    );
+                }
+                if !self.containers.iter().any(|c| c.name == target) {
+                    bail!(
+                        "Container '{}' shares the network of unknown container '{}'",
+                        container.name,
+                        target
+                    );
+                }
+                if !container.ports.is_empty() {
+                    bail!(
+                        "Container '{}' can't publish ports while sharing the network of '{}'",
+                        container.name,
+                        target
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Orders the containers so that every container comes after the ones it depends on,
+    /// including the one whose network stack it joins.
+    /// Containers without a dependency between them keep their order from the schedule.
+    pub fn start_order(&self) -> Result<Vec<&Container>, anyhow::Error> {
+        let mut positions = HashMap::new();
+        for (position, container) in self.containers.iter().enumerate() {
+            if positions
+                .insert(container.name.as_str(), position)
+                .is_some()
+            {
+                bail!("Duplicate container name '{}'", container.name);
+            }
+        }
+
+        for container in &self.containers {
+            for dependency in container.dependencies() {
+                if !positions.contains_key(dependency) {
+                    bail!(
+                        "Container '{}' depends on unknown container '{}'",
+                        container.name,
+                        dependency
+                    );
+                }
+            }
+        }
+
+        let mut started = vec![false; self.containers.len()];
+        let mut order = Vec::with_capacity(self.containers.len());
+
+        while order.len() < self.containers.len() {
+            let next = self
+                .containers
+                .iter()
+                .enumerate()
+                .position(|(i, container)| {
+                    !started[i]
+                        && container
+                            .dependencies()
+                            .all(|dependency| started[positions[dependency]])
+                });
+
+            match next {
+                Some(i) => {
+                    started[i] = true;
+                    order.push(&self.containers[i]);
+                }
+                None => {
+                    let remaining: Vec<&str> = self
+                        .containers
+                        .iter()
+                        .enumerate()
+                        .filter(|(i, _)| !started[*i])
+                        .map(|(_, container)| container.name.as_str())
+                        .collect();
+                    bail!(
+                        "Dependency cycle between containers: {}",
+                        remaining.join(", ")
+                    );
+                }
+            }
+        }
+
+        Ok(order)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::schedule::Spec;
+    use crate::grpc_remote::{RestartPolicy, Schedule};
+    use std::time::Duration;
+
+    use crate::schedule::{
+        parse_byte_size, parse_duration, validate_device_cgroup_rule, DeviceSpec, RestartSpec,
+        Spec, UlimitSpec, VolumeHostPath, VolumeSpec,
+    };
 
     #[test]
     fn test_example_spec() {
@@ -405,12 +1704,12 @@ services:
         cap_drop:
             - MKNOD
             - AUDIT_CONTROL
-        # healthcheck:
-        #     test: ["CMD", "curl", "-f", "http://localhost"]
-        #     interval: 30s
-        #     timeout: 10s
-        #     retries: 3
-        #     start_period: 40s
+        healthcheck:
+            test: ["CMD", "curl", "-f", "http://localhost"]
+            interval: 30s
+            timeout: 10s
+            retries: 3
+            start_period: 40s
         host_features:
             daemon_socket: true
             boot_partition: true
@@ -418,6 +1717,8 @@ services:
 
         let spec: Spec = serde_yaml::from_str(EXAMPLE_SPEC).unwrap();
         assert_eq!(spec.version, "0.1.0");
+        assert_eq!(spec.services[0].cap_add, vec!["NET_ADMIN", "SYS_ADMIN"]);
+        assert_eq!(spec.services[0].cap_drop, vec!["MKNOD", "AUDIT_CONTROL"]);
     }
 
     #[test]
@@ -433,4 +1734,744 @@ services:
         let spec: Spec = serde_yaml::from_str(SIMPLE_SPEC).unwrap();
         assert_eq!(spec.version, "0.1.0");
     }
+
+    #[test]
+    fn test_volumes_round_trip() {
+        const VOLUME_SPEC: &str = r#"
+version: 0.1.0
+services:
+    -
+        name: app
+        image: app:latest
+        volumes:
+            - app-data:/data
+            - /var/log/app:/logs
+            - /cache
+            - /etc/ssl:/etc/ssl:ro
+            - app-config:/config:rw
+    "#;
+
+        let spec: Spec = serde_yaml::from_str(VOLUME_SPEC).unwrap();
+        let schedule = Schedule::from_spec(&spec);
+
+        let volumes = &schedule.containers[0].volumes;
+        assert_eq!(volumes.len(), 5);
+        assert_eq!(volumes[0].source, "app-data");
+        assert_eq!(volumes[0].container_path, "/data");
+        assert_eq!(volumes[1].source, "/var/log/app");
+        assert_eq!(volumes[2].source, "");
+        assert_eq!(volumes[2].container_path, "/cache");
+        assert!(!volumes[1].read_only);
+        assert_eq!(volumes[3].source, "/etc/ssl");
+        assert_eq!(volumes[3].container_path, "/etc/ssl");
+        assert!(volumes[3].read_only);
+        assert_eq!(volumes[4].source, "app-config");
+        assert!(!volumes[4].read_only);
+
+        let round_tripped = Spec::from_schedule(&schedule).unwrap();
+        let volumes = &round_tripped.services[0].volumes;
+        assert!(matches!(&volumes[0].host_path, VolumeHostPath::Named(name) if name == "app-data"));
+        assert!(matches!(volumes[2].host_path, VolumeHostPath::Anonymous));
+        assert_eq!(volumes[2].container_path, "/cache");
+        assert!(volumes[3].read_only);
+
+        let err = "/etc/ssl:/etc/ssl:z".parse::<VolumeSpec>().unwrap_err();
+        assert!(err.starts_with("Invalid volume mode"), "{}", err);
+
+        for relative in [
+            "./data:/data",
+            "../data:/data",
+            "~/data:/data",
+            "data/sub:/data",
+        ] {
+            let err = relative.parse::<VolumeSpec>().unwrap_err();
+            assert!(err.starts_with("Relative bind mounts"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_entrypoint_and_privileges() {
+        const ENTRYPOINT_SPEC: &str = r#"
+version: 0.1.0
+services:
+    -
+        name: camera
+        image: camera:latest
+        entrypoint: ["/usr/bin/tini", "--"]
+        privileged: true
+        host_features:
+            boot_partition: true
+            udev: true
+            kernel_modules: true
+    "#;
+
+        let spec: Spec = serde_yaml::from_str(ENTRYPOINT_SPEC).unwrap();
+        let schedule = Schedule::from_spec(&spec);
+
+        let container = &schedule.containers[0];
+        assert_eq!(container.entrypoint_args, vec!["/usr/bin/tini", "--"]);
+        assert!(container.entrypoint.is_empty());
+
+        // Schedules stored before entrypoint_args existed still load
+        let stored: crate::grpc_remote::Container =
+            serde_json::from_str(r#"{"name":"camera","entrypoint":"/init"}"#).unwrap();
+        assert_eq!(stored.entrypoint_command(), vec!["/init"]);
+        let stored: crate::grpc_remote::Container =
+            serde_json::from_str(r#"{"name":"camera","entrypoint":""}"#).unwrap();
+        assert!(stored.entrypoint_command().is_empty());
+        assert!(container.privileged);
+        assert!(container.bind_boot);
+        assert!(!container.bind_docker_socket);
+        assert!(container.bind_udev);
+        assert!(container.bind_kernel_modules);
+        assert!(!container.bind_journal_logs);
+        assert!(!container.bind_dbus);
+
+        let round_tripped = Spec::from_schedule(&schedule).unwrap();
+        assert!(round_tripped.services[0].host_features.udev);
+        assert!(!round_tripped.services[0].host_features.dbus);
+    }
+
+    #[test]
+    fn test_restart_policies() {
+        assert_eq!("".parse::<RestartSpec>(), Ok(RestartSpec::Unspecified));
+        assert_eq!("always".parse::<RestartSpec>(), Ok(RestartSpec::Always));
+        assert_eq!(
+            "on-failure".parse::<RestartSpec>(),
+            Ok(RestartSpec::OnFailure(0))
+        );
+        assert_eq!(
+            "on-failure:5".parse::<RestartSpec>(),
+            Ok(RestartSpec::OnFailure(5))
+        );
+        assert!("on-failure:many".parse::<RestartSpec>().is_err());
+        assert!("sometimes".parse::<RestartSpec>().is_err());
+
+        const RESTART_SPEC: &str = r#"
+version: 0.1.0
+services:
+    -
+        name: worker
+        image: worker:latest
+        restart: on-failure:3
+    -
+        name: oneshot
+        image: oneshot:latest
+        restart: no
+    -
+        name: default
+        image: default:latest
+    "#;
+
+        let spec: Spec = serde_yaml::from_str(RESTART_SPEC).unwrap();
+        let schedule = Schedule::from_spec(&spec);
+
+        assert_eq!(
+            schedule.containers[0].restart_policy(),
+            RestartPolicy::OnFailure
+        );
+        assert_eq!(schedule.containers[0].restart_max_retries, 3);
+        assert_eq!(schedule.containers[1].restart_policy(), RestartPolicy::No);
+        assert_eq!(
+            schedule.containers[2].restart_policy(),
+            RestartPolicy::Unspecified
+        );
+
+        let round_tripped = Spec::from_schedule(&schedule).unwrap();
+        assert_eq!(round_tripped.services[0].restart, RestartSpec::OnFailure(3));
+    }
+
+    #[test]
+    fn test_start_order() {
+        const DEPENDENCY_SPEC: &str = r#"
+version: 0.1.0
+services:
+    -
+        name: app
+        image: app:latest
+        depends_on:
+            - db
+            - cache
+    -
+        name: cache
+        image: redis:latest
+    -
+        name: db
+        image: postgres:latest
+    "#;
+
+        let spec: Spec = serde_yaml::from_str(DEPENDENCY_SPEC).unwrap();
+        spec.validate().unwrap();
+
+        let schedule = Schedule::from_spec(&spec);
+        let order: Vec<&str> = schedule
+            .start_order()
+            .unwrap()
+            .iter()
+            .map(|container| container.name.as_str())
+            .collect();
+        assert_eq!(order, vec!["cache", "db", "app"]);
+    }
+
+    #[test]
+    fn test_invalid_dependencies() {
+        const UNKNOWN_SPEC: &str = r#"
+version: 0.1.0
+services:
+    -
+        name: app
+        image: app:latest
+        depends_on:
+            - db
+    "#;
+
+        let spec: Spec = serde_yaml::from_str(UNKNOWN_SPEC).unwrap();
+        let err = spec.validate().unwrap_err().to_string();
+        assert!(err.contains("unknown container 'db'"), "{}", err);
+
+        const CYCLE_SPEC: &str = r#"
+version: 0.1.0
+services:
+    -
+        name: a
+        image: a:latest
+        depends_on: [b]
+    -
+        name: b
+        image: b:latest
+        depends_on: [a]
+    -
+        name: c
+        image: c:latest
+    "#;
+
+        let spec: Spec = serde_yaml::from_str(CYCLE_SPEC).unwrap();
+        let err = spec.validate().unwrap_err().to_string();
+        assert_eq!(err, "Dependency cycle between containers: a, b");
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("1m30s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("30").is_err());
+        assert!(parse_duration("30 days").is_err());
+        assert!(parse_duration("99999999999999999999h").is_err());
+        assert!(parse_duration("5124095576030431h5124095576030431h").is_err());
+
+        let spec = "version: 0.1.0\nservices:\n    - {name: app, image: app, stop_grace_period: 99999999999999999999h}\n";
+        assert!(serde_yaml::from_str::<Spec>(spec).is_err());
+    }
+
+    #[test]
+    fn test_healthcheck() {
+        const HEALTHCHECK_SPEC: &str = r#"
+version: 0.1.0
+services:
+    -
+        name: web
+        image: web:latest
+        healthcheck:
+            test: curl -f http://localhost
+            interval: 30s
+            timeout: 5s
+            retries: 3
+            start_period: 1m
+    -
+        name: quiet
+        image: quiet:latest
+        healthcheck:
+            disable: true
+    "#;
+
+        let spec: Spec = serde_yaml::from_str(HEALTHCHECK_SPEC).unwrap();
+        let schedule = Schedule::from_spec(&spec);
+
+        let healthcheck = schedule.containers[0].healthcheck.as_ref().unwrap();
+        assert_eq!(
+            healthcheck.test,
+            vec!["CMD-SHELL", "curl -f http://localhost"]
+        );
+        assert_eq!(healthcheck.interval_ms, 30_000);
+        assert_eq!(healthcheck.timeout_ms, 5_000);
+        assert_eq!(healthcheck.retries, 3);
+        assert_eq!(healthcheck.start_period_ms, 60_000);
+
+        let disabled = schedule.containers[1].healthcheck.as_ref().unwrap();
+        assert_eq!(disabled.test, vec!["NONE"]);
+
+        let round_tripped = Spec::from_schedule(&schedule).unwrap();
+        let healthcheck = round_tripped.services[0].healthcheck.as_ref().unwrap();
+        assert_eq!(healthcheck.interval, Some(Duration::from_secs(30)));
+        assert!(
+            round_tripped.services[1]
+                .healthcheck
+                .as_ref()
+                .unwrap()
+                .disable
+        );
+    }
+
+    #[test]
+    fn test_serialize_round_trip() {
+        const ROUND_TRIP_SPEC: &str = r#"
+version: 0.1.0
+services:
+    -
+        name: web
+        image: nginx:latest
+        restart: on-failure:3
+        healthcheck:
+            test: curl -f http://localhost/
+            interval: 1m30s
+            timeout: 500ms
+            start_period: 250us
+        ulimits:
+            nproc: 65535
+            nofile:
+                soft: 20000
+                hard: 40000
+        stop_grace_period: 10s
+    -
+        name: job
+        image: job:latest
+        restart: no
+    -
+        name: plain
+        image: plain:latest
+    "#;
+
+        let spec: Spec = serde_yaml::from_str(ROUND_TRIP_SPEC).unwrap();
+        let yaml = serde_yaml::to_string(&spec).unwrap();
+        let round_tripped: Spec = serde_yaml::from_str(&yaml).unwrap();
+
+        assert_eq!(
+            format!("{:?}", round_tripped.services),
+            format!("{:?}", spec.services),
+            "{}",
+            yaml
+        );
+        assert_eq!(round_tripped.services[0].restart, RestartSpec::OnFailure(3));
+        assert_eq!(round_tripped.services[1].restart, RestartSpec::No);
+        assert_eq!(round_tripped.services[2].restart, RestartSpec::Unspecified);
+        let healthcheck = round_tripped.services[0].healthcheck.as_ref().unwrap();
+        assert_eq!(healthcheck.interval, Some(Duration::from_secs(90)));
+        assert_eq!(healthcheck.timeout, Some(Duration::from_millis(500)));
+        assert_eq!(healthcheck.start_period, Some(Duration::from_micros(250)));
+    }
+
+    #[test]
+    fn test_sysctls_and_ulimits() {
+        const LIMITS_SPEC: &str = r#"
+version: 0.1.0
+services:
+    -
+        name: proxy
+        image: proxy:latest
+        sysctls:
+            net.core.somaxconn: 1024
+            net.ipv4.tcp_syncookies: "0"
+        ulimits:
+            nproc: 65535
+            nofile:
+                soft: 20000
+                hard: 40000
+    -
+        name: listed
+        image: listed:latest
+        sysctls:
+            - net.ipv4.ip_forward=1
+    "#;
+
+        let spec: Spec = serde_yaml::from_str(LIMITS_SPEC).unwrap();
+        assert_eq!(
+            spec.services[0].sysctls,
+            vec![
+                ("net.core.somaxconn".to_string(), "1024".to_string()),
+                ("net.ipv4.tcp_syncookies".to_string(), "0".to_string()),
+            ]
+        );
+        assert_eq!(
+            spec.services[0].ulimits,
+            vec![
+                UlimitSpec {
+                    name: "nofile".to_string(),
+                    soft: 20000,
+                    hard: 40000,
+                },
+                UlimitSpec {
+                    name: "nproc".to_string(),
+                    soft: 65535,
+                    hard: 65535,
+                },
+            ]
+        );
+        assert_eq!(
+            spec.services[1].sysctls,
+            vec![("net.ipv4.ip_forward".to_string(), "1".to_string())]
+        );
+
+        let schedule = Schedule::from_spec(&spec);
+        assert_eq!(schedule.containers[0].ulimits[0].name, "nofile");
+        assert_eq!(schedule.containers[0].sysctls[0].value, "1024");
+
+        const INVERTED_SPEC: &str = r#"
+version: 0.1.0
+services:
+    -
+        name: proxy
+        image: proxy:latest
+        ulimits:
+            nofile:
+                soft: 40000
+                hard: 20000
+    "#;
+
+        assert!(serde_yaml::from_str::<Spec>(INVERTED_SPEC).is_err());
+    }
+
+    #[test]
+    fn test_stop_settings() {
+        const STOP_SPEC: &str = r#"
+version: 0.1.0
+services:
+    -
+        name: db
+        image: postgres:latest
+        stop_grace_period: 1m
+        stop_signal: SIGINT
+    "#;
+
+        let spec: Spec = serde_yaml::from_str(STOP_SPEC).unwrap();
+        let schedule = Schedule::from_spec(&spec);
+        assert_eq!(schedule.containers[0].stop_grace_period_ms, 60_000);
+        assert_eq!(schedule.containers[0].stop_signal, "SIGINT");
+
+        let round_tripped = Spec::from_schedule(&schedule).unwrap();
+        assert_eq!(
+            round_tripped.services[0].stop_grace_period,
+            Some(Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn test_resource_limits() {
+        const LIMITS_SPEC: &str = r#"
+version: 0.1.0
+services:
+    -
+        name: app
+        image: app:latest
+        mem_limit: 256m
+        memswap_limit: -1
+        cpus: 1.5
+        cpu_shares: 512
+        pids_limit: 100
+        oom_score_adj: -500
+    "#;
+
+        let spec: Spec = serde_yaml::from_str(LIMITS_SPEC).unwrap();
+        spec.validate().unwrap();
+
+        let schedule = Schedule::from_spec(&spec);
+        let container = &schedule.containers[0];
+        assert_eq!(container.mem_limit_bytes, 256 * 1024 * 1024);
+        assert_eq!(container.memswap_limit_bytes, -1);
+        assert_eq!(container.nano_cpus, 1_500_000_000);
+        assert_eq!(container.cpu_shares, 512);
+        assert_eq!(container.pids_limit, 100);
+        assert_eq!(container.oom_score_adj, -500);
+
+        let round_tripped = Spec::from_schedule(&schedule).unwrap();
+        assert_eq!(round_tripped.services[0].mem_limit, Some(256 * 1024 * 1024));
+        assert_eq!(round_tripped.services[0].memswap_limit, Some(-1));
+        assert_eq!(round_tripped.services[0].cpus, Some(1.5));
+
+        assert_eq!(parse_byte_size("1024"), Ok(1024));
+        assert_eq!(parse_byte_size("1.5g"), Ok(1536 * 1024 * 1024));
+        assert_eq!(parse_byte_size("64MB"), Ok(64 * 1024 * 1024));
+        assert!(parse_byte_size("lots").is_err());
+
+        let invalid = |limits: &str| {
+            let yaml = format!(
+                "version: 0.1.0\nservices:\n  - name: app\n    image: app:latest\n{}",
+                limits
+            );
+            let spec: Spec = serde_yaml::from_str(&yaml).unwrap();
+            spec.validate().unwrap_err().to_string()
+        };
+        assert!(invalid("    mem_limit: 1m\n").contains("at least 6m"));
+        assert!(invalid("    memswap_limit: 1g\n").contains("requires mem_limit"));
+        assert!(invalid("    mem_limit: 1g\n    memswap_limit: 512m\n").contains("at least"));
+        assert!(invalid("    cpus: 0\n").contains("cpus"));
+        assert!(invalid("    cpus: 0.001\n").contains("at least 0.01"));
+        assert!(invalid("    cpu_shares: 1\n").contains("cpu_shares"));
+        assert!(invalid("    pids_limit: 0\n").contains("pids_limit"));
+        assert!(invalid("    oom_score_adj: 2000\n").contains("oom_score_adj"));
+    }
+
+    #[test]
+    fn test_networks() {
+        const NETWORK_SPEC: &str = r#"
+version: 0.1.0
+networks:
+    backend:
+        internal: true
+        driver_opts:
+            com.docker.network.driver.mtu: 1400
+        ipam:
+            config:
+                - subnet: 172.28.0.0/16
+                  gateway: 172.28.0.1
+    frontend:
+services:
+    -
+        name: db
+        image: postgres:16
+        networks:
+            frontend:
+            backend:
+                aliases: [database]
+    -
+        name: web
+        image: nginx:latest
+        networks: [frontend, backend]
+    -
+        name: sidecar
+        image: proxy:latest
+        network_mode: container:web
+    -
+        name: monitor
+        image: monitor:latest
+        networks: [host]
+    "#;
+
+        let spec: Spec = serde_yaml::from_str(NETWORK_SPEC).unwrap();
+        spec.validate().unwrap();
+
+        let schedule = Schedule::from_spec(&spec);
+        assert_eq!(schedule.networks.len(), 2);
+        let backend = &schedule.networks[0];
+        assert_eq!(backend.name, "backend");
+        assert!(backend.internal);
+        assert_eq!(backend.driver_opts[0].value, "1400");
+        assert_eq!(backend.ipam_pools[0].gateway, "172.28.0.1");
+        assert_eq!(schedule.networks[1].driver, "");
+
+        // Declaration order is kept, so frontend is the network db is created on
+        let db = &schedule.containers[0].networks;
+        assert_eq!(db[0].name, "frontend");
+        assert!(db[0].aliases.is_empty());
+        assert_eq!(db[1].aliases, vec!["database"]);
+        let web: Vec<&str> = schedule.containers[1]
+            .networks
+            .iter()
+            .map(|n| n.name.as_str())
+            .collect();
+        assert_eq!(web, vec!["frontend", "backend"]);
+        assert_eq!(schedule.containers[2].network_mode, "container:web");
+        assert_eq!(schedule.containers[3].network_mode, "host");
+        assert!(schedule.containers[3].networks.is_empty());
+
+        // The container sharing a network stack starts after the one it joins
+        let order: Vec<&str> = schedule
+            .start_order()
+            .unwrap()
+            .iter()
+            .map(|c| c.name.as_str())
+            .collect();
+        assert_eq!(order, vec!["db", "web", "sidecar", "monitor"]);
+
+        // Redefining a network changes the hash of the containers attached to it
+        let web = &schedule.containers[1];
+        let mut redefined = schedule.clone();
+        redefined.networks[0].internal = false;
+        assert_ne!(schedule.container_hash(web), redefined.container_hash(web));
+        let sidecar = &schedule.containers[2];
+        assert_eq!(schedule.container_hash(sidecar), sidecar.definition_hash());
+
+        let round_trip = Schedule::from_spec(&Spec::from_schedule(&schedule).unwrap());
+        assert_eq!(round_trip.networks, schedule.networks);
+        assert_eq!(round_trip.containers, schedule.containers);
+
+        for (yaml, expected) in [
+            (
+                "networks: [missing]",
+                "Container 'app' uses undefined network 'missing'",
+            ),
+            (
+                "network_mode: container:ghost",
+                "Container 'app' shares the network of unknown container 'ghost'",
+            ),
+            (
+                "network_mode: host\n        networks: [default]",
+                "Container 'app' can't combine networks with network_mode 'host'",
+            ),
+        ] {
+            let spec: Spec = serde_yaml::from_str(&format!(
+                "version: 0.1.0\nservices:\n    -\n        name: app\n        image: app:latest\n        {}\n",
+                yaml
+            ))
+            .unwrap();
+            assert_eq!(spec.validate().unwrap_err().to_string(), expected);
+        }
+
+        assert!(serde_yaml::from_str::<Spec>(
+            "version: 0.1.0\nservices:\n    - {name: app, image: app, network_mode: bogus}\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_devices() {
+        const DEVICE_SPEC: &str = r#"
+version: 0.1.0
+services:
+    -
+        name: sensor
+        image: sensor:latest
+        devices:
+            - /dev/ttyUSB0
+            - /dev/i2c-1:/dev/i2c:rw
+            - /dev/gpiomem:r
+        device_cgroup_rules:
+            - c 188:* rmw
+            - a *:* r
+    "#;
+
+        let spec: Spec = serde_yaml::from_str(DEVICE_SPEC).unwrap();
+        spec.validate().unwrap();
+
+        let schedule = Schedule::from_spec(&spec);
+        let devices = &schedule.containers[0].devices;
+        assert_eq!(devices.len(), 3);
+        assert_eq!(devices[0].container_path, "/dev/ttyUSB0");
+        assert_eq!(devices[0].cgroup_permissions, "rwm");
+        assert_eq!(devices[1].host_path, "/dev/i2c-1");
+        assert_eq!(devices[1].container_path, "/dev/i2c");
+        assert_eq!(devices[1].cgroup_permissions, "rw");
+        assert_eq!(devices[2].container_path, "/dev/gpiomem");
+        assert_eq!(devices[2].cgroup_permissions, "r");
+        assert_eq!(
+            schedule.containers[0].device_cgroup_rules,
+            vec!["c 188:* rmw", "a *:* r"]
+        );
+
+        let round_tripped = Schedule::from_spec(&Spec::from_schedule(&schedule).unwrap());
+        assert_eq!(round_tripped.containers, schedule.containers);
+
+        assert!("dev/ttyUSB0".parse::<DeviceSpec>().is_err());
+        assert!("/dev/ttyUSB0:/dev/tty:rwx".parse::<DeviceSpec>().is_err());
+
+        for rule in ["c 188 rmw", "x 1:2 r", "c 1:2 rwx", "c 1:2"] {
+            assert!(validate_device_cgroup_rule(rule).is_err(), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn test_registry_credentials() {
+        const REGISTRY_SPEC: &str = r#"
+version: 0.1.0
+registry_credentials:
+    -
+        registry: ghcr.io
+        username: deploy
+        password: hunter2
+services:
+    -
+        name: app
+        image: ghcr.io/uinta-labs/app:latest
+    "#;
+
+        let spec: Spec = serde_yaml::from_str(REGISTRY_SPEC).unwrap();
+        let schedule = Schedule::from_spec(&spec);
+        assert_eq!(schedule.registry_credentials[0].registry, "ghcr.io");
+        assert_eq!(schedule.registry_credentials[0].password, "hunter2");
+        assert!(!format!("{:?}", schedule).contains("hunter2"));
+    }
+
+    #[test]
+    fn test_definition_hash() {
+        const HASH_SPEC: &str = r#"
+version: 0.1.0
+services:
+    -
+        name: web
+        image: nginx:1.27
+        environment:
+            - MODE=production
+    "#;
+
+        let spec: Spec = serde_yaml::from_str(HASH_SPEC).unwrap();
+        let container = Schedule::from_spec(&spec).containers.remove(0);
+        assert_eq!(
+            container.definition_hash(),
+            container.clone().definition_hash()
+        );
+
+        let mut changed_image = container.clone();
+        changed_image.container_image = "nginx:1.28".to_string();
+        assert_ne!(container.definition_hash(), changed_image.definition_hash());
+
+        let mut changed_environment = container.clone();
+        changed_environment.environment[0].value = "debug".to_string();
+        assert_ne!(
+            container.definition_hash(),
+            changed_environment.definition_hash()
+        );
+    }
+
+    #[test]
+    fn test_stable_task_ids() {
+        const ID_SPEC: &str = r#"
+version: 0.1.0
+services:
+    -
+        name: web
+        image: nginx:latest
+    -
+        name: web-renamed
+        id: api
+        image: api:latest
+    "#;
+
+        let spec: Spec = serde_yaml::from_str(ID_SPEC).unwrap();
+        spec.validate().unwrap();
+
+        let first = Schedule::from_spec(&spec);
+        let second = Schedule::from_spec(&spec);
+        assert_eq!(first.containers[0].id, "web");
+        assert_eq!(first.containers[1].id, "api");
+        assert_eq!(first, second);
+
+        // A rename keeps the task but changes its definition, so the container is recreated
+        let mut renamed = spec.clone();
+        renamed.services[1].name = "api-server".to_string();
+        let renamed = Schedule::from_spec(&renamed);
+        assert_eq!(renamed.containers[1].id, "api");
+        assert_ne!(
+            renamed.containers[1].definition_hash(),
+            first.containers[1].definition_hash()
+        );
+
+        const DUPLICATE_SPEC: &str = r#"
+version: 0.1.0
+services:
+    -
+        name: web
+        image: nginx:latest
+    -
+        name: other
+        id: web
+        image: other:latest
+    "#;
+
+        let spec: Spec = serde_yaml::from_str(DUPLICATE_SPEC).unwrap();
+        let err = spec.validate().unwrap_err().to_string();
+        assert_eq!(err, "Duplicate service id 'web'");
+    }
 }
@@ -0,0 +1,134 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use prost::Message;
+
+use crate::grpc_remote::Schedule;
+
+/// Keeps the last successfully applied schedule on disk so the device can bring its workload back
+/// up after a reboot, before any network is available.
+#[derive(Debug, Clone)]
+pub struct ScheduleStore {
+    path: PathBuf,
+}
+
+impl ScheduleStore {
+    pub fn new(dir: &Path, file_name: &str) -> Self {
+        ScheduleStore {
+            path: dir.join(file_name),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Loads the persisted schedule, or `None` if nothing has been applied yet.
+    pub fn load(&self) -> io::Result<Option<Schedule>> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        Schedule::decode(bytes.as_slice()).map(Some).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to decode persisted schedule: {}", e),
+            )
+        })
+    }
+
+    /// Replaces the persisted schedule. The new copy is written to a temporary file and renamed
+    /// over the old one, so a power cut leaves either the old or the new schedule, never half of
+    /// one.
+    ///
+    /// Registry credentials are left out so they never reach the disk. Restoring only needs the
+    /// images already on the device; anything missing is pulled with the device's own credentials.
+    pub fn save(&self, schedule: &Schedule) -> io::Result<()> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        fs::create_dir_all(dir)?;
+
+        let schedule = Schedule {
+            registry_credentials: Vec::new(),
+            ..schedule.clone()
+        };
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&schedule.encode_to_vec())?;
+        file.sync_all()?;
+        drop(file);
+
+        fs::rename(&tmp_path, &self.path)?;
+
+        // The rename itself only survives a power cut once the directory entry is on disk
+        #[cfg(unix)]
+        fs::File::open(dir)?.sync_all()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::grpc_remote::{Container, RegistryCredential};
+
+    #[test]
+    fn test_save_and_supersede() -> io::Result<()> {
+        let dir = assert_fs::TempDir::new().unwrap();
+        let store = ScheduleStore::new(dir.path(), "schedule.pb");
+
+        assert_eq!(store.load()?, None);
+
+        let first = Schedule {
+            id: "first".to_string(),
+            current: true,
+            containers: vec![Container {
+                id: "web".to_string(),
+                name: "web".to_string(),
+                container_image: "nginx:latest".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        store.save(&first)?;
+        assert_eq!(store.load()?, Some(first));
+
+        let mut second = Schedule {
+            id: "second".to_string(),
+            current: true,
+            registry_credentials: vec![RegistryCredential {
+                registry: "registry.example.com".to_string(),
+                username: "device".to_string(),
+                password: "secret".to_string(),
+            }],
+            ..Default::default()
+        };
+        store.save(&second)?;
+        // Credentials are never written out
+        second.registry_credentials.clear();
+        assert_eq!(store.load()?, Some(second));
+        assert!(!dir.path().join("schedule.pb.tmp").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_schedule() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        let store = ScheduleStore::new(dir.path(), "schedule.pb");
+        fs::write(store.path(), b"\xff\xff\xff").unwrap();
+
+        assert_eq!(store.load().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
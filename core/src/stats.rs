@@ -0,0 +1,541 @@
+use std::collections::{BTreeMap, HashSet};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::temp::{list_zones, Temperature};
+
+/// Bumped whenever the shape of `SystemStats` changes incompatibly. Version 1 was the bare
+/// `cpu_temp` message.
+pub const STATS_VERSION: u32 = 2;
+
+/// Filesystems that don't correspond to storage worth watching.
+const PSEUDO_FILESYSTEMS: &[&str] = &[
+    "autofs",
+    "bpf",
+    "cgroup",
+    "cgroup2",
+    "configfs",
+    "debugfs",
+    "devpts",
+    "devtmpfs",
+    "fusectl",
+    "hugetlbfs",
+    "mqueue",
+    "nsfs",
+    "overlay",
+    "proc",
+    "pstore",
+    "securityfs",
+    "squashfs",
+    "sysfs",
+    "tmpfs",
+    "tracefs",
+];
+
+/// Point-in-time health of the device, published periodically and returned for `get-stats`.
+/// Sections the host couldn't provide are left empty rather than failing the whole snapshot.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SystemStats {
+    pub version: u32,
+    /// Unix timestamp of the snapshot
+    pub timestamp: i64,
+    pub uptime_secs: Option<f64>,
+    pub load_average: Option<LoadAverage>,
+    pub memory: Option<MemoryStats>,
+    pub swap: Option<SwapStats>,
+    pub disks: Vec<DiskStats>,
+    pub network: Vec<NetworkInterfaceStats>,
+    /// Temperature in °C, keyed by thermal zone type (e.g. `cpu-thermal`)
+    pub thermal_zones: BTreeMap<String, f64>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoadAverage {
+    pub one: f64,
+    pub five: f64,
+    pub fifteen: f64,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MemoryStats {
+    pub total_bytes: u64,
+    pub available_bytes: u64,
+    pub used_bytes: u64,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SwapStats {
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+    pub used_bytes: u64,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DiskStats {
+    pub mount_point: String,
+    pub device: String,
+    pub filesystem: String,
+    pub total_bytes: u64,
+    pub available_bytes: u64,
+    pub used_bytes: u64,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetworkInterfaceStats {
+    pub name: String,
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub rx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_errors: u64,
+    pub tx_dropped: u64,
+}
+
+/// Resource usage of every managed container, published next to `SystemStats`.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContainerStatsReport {
+    pub version: u32,
+    /// Unix timestamp of the snapshot
+    pub timestamp: i64,
+    /// Keyed by task id
+    pub containers: BTreeMap<String, ContainerStats>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContainerStats {
+    pub name: String,
+    pub container_id: String,
+    pub restart_count: i64,
+    pub last_exit_code: Option<i64>,
+    /// Only sampled while the container is running
+    pub usage: Option<ContainerResourceUsage>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContainerResourceUsage {
+    /// Share of a single CPU, so a container saturating two cores reports 200
+    pub cpu_percent: f64,
+    pub memory_usage_bytes: u64,
+    pub memory_limit_bytes: u64,
+    pub network_rx_bytes: u64,
+    pub network_tx_bytes: u64,
+    pub block_read_bytes: u64,
+    pub block_write_bytes: u64,
+}
+
+impl ContainerResourceUsage {
+    /// Summarizes a stats sample the way `docker stats` does. The sample needs its `precpu_stats`
+    /// filled in, i.e. it must not have been taken with `one_shot`.
+    pub fn from_docker(stats: &bollard::container::Stats) -> Self {
+        let cpu_delta = stats
+            .cpu_stats
+            .cpu_usage
+            .total_usage
+            .saturating_sub(stats.precpu_stats.cpu_usage.total_usage);
+        let system_delta = stats
+            .cpu_stats
+            .system_cpu_usage
+            .unwrap_or_default()
+            .saturating_sub(stats.precpu_stats.system_cpu_usage.unwrap_or_default());
+        let online_cpus = stats.cpu_stats.online_cpus.unwrap_or_else(|| {
+            stats
+                .cpu_stats
+                .cpu_usage
+                .percpu_usage
+                .as_ref()
+                .map(|percpu| percpu.len() as u64)
+                .unwrap_or(1)
+        });
+
+        // Page cache is reclaimable, so it doesn't count towards usage
+        let cache = match stats.memory_stats.stats {
+            Some(bollard::container::MemoryStatsStats::V1(v1)) => v1.total_inactive_file,
+            Some(bollard::container::MemoryStatsStats::V2(v2)) => v2.inactive_file,
+            None => 0,
+        };
+
+        // Engines older than API 1.21 only report the single legacy `network`
+        let networks: Vec<&bollard::container::NetworkStats> = match &stats.networks {
+            Some(networks) => networks.values().collect(),
+            None => stats.network.iter().collect(),
+        };
+        let (network_rx_bytes, network_tx_bytes) =
+            networks.into_iter().fold((0, 0), |(rx, tx), network| {
+                (rx + network.rx_bytes, tx + network.tx_bytes)
+            });
+
+        let (block_read_bytes, block_write_bytes) = stats
+            .blkio_stats
+            .io_service_bytes_recursive
+            .iter()
+            .flatten()
+            .fold((0, 0), |(read, write), entry| {
+                match entry.op.to_ascii_lowercase().as_str() {
+                    "read" => (read + entry.value, write),
+                    "write" => (read, write + entry.value),
+                    _ => (read, write),
+                }
+            });
+
+        ContainerResourceUsage {
+            cpu_percent: cpu_percent(cpu_delta, system_delta, online_cpus),
+            memory_usage_bytes: stats
+                .memory_stats
+                .usage
+                .unwrap_or_default()
+                .saturating_sub(cache),
+            memory_limit_bytes: stats.memory_stats.limit.unwrap_or_default(),
+            network_rx_bytes,
+            network_tx_bytes,
+            block_read_bytes,
+            block_write_bytes,
+        }
+    }
+}
+
+fn cpu_percent(cpu_delta: u64, system_delta: u64, online_cpus: u64) -> f64 {
+    if cpu_delta == 0 || system_delta == 0 {
+        return 0.0;
+    }
+
+    cpu_delta as f64 / system_delta as f64 * online_cpus as f64 * 100.0
+}
+
+impl SystemStats {
+    /// Takes a fresh snapshot.
+    pub async fn collect() -> Result<Self> {
+        let meminfo = read_proc("/proc/meminfo").await;
+
+        Ok(SystemStats {
+            version: STATS_VERSION,
+            timestamp: chrono::Utc::now().timestamp(),
+            uptime_secs: read_proc("/proc/uptime")
+                .await
+                .and_then(|uptime| parse_uptime(&uptime)),
+            load_average: read_proc("/proc/loadavg")
+                .await
+                .and_then(|loadavg| parse_loadavg(&loadavg)),
+            memory: meminfo.as_deref().and_then(parse_memory),
+            swap: meminfo.as_deref().and_then(parse_swap),
+            disks: match read_proc("/proc/mounts").await {
+                Some(mounts) => collect_disks(&mounts),
+                None => vec![],
+            },
+            network: read_proc("/proc/net/dev")
+                .await
+                .map(|net_dev| parse_net_dev(&net_dev))
+                .unwrap_or_default(),
+            thermal_zones: collect_thermal_zones().await,
+        })
+    }
+}
+
+async fn read_proc(path: &str) -> Option<String> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(contents) => Some(contents),
+        Err(e) => {
+            debug!("Unable to read {}: {}", path, e);
+            None
+        }
+    }
+}
+
+async fn collect_thermal_zones() -> BTreeMap<String, f64> {
+    let mut thermal_zones = BTreeMap::new();
+
+    let zones = match list_zones().await {
+        Ok(zones) => zones,
+        Err(e) => {
+            debug!("Unable to list thermal zones: {}", e);
+            return thermal_zones;
+        }
+    };
+
+    for zone in zones {
+        let temperature = Temperature::new(zone.clone());
+        let Ok(temp) = temperature.get_temperature().await else {
+            continue;
+        };
+
+        let zone_type = temperature
+            .get_zone_type()
+            .await
+            .unwrap_or_else(|_| zone.clone());
+
+        // Some boards expose several zones of the same type
+        let key = if thermal_zones.contains_key(&zone_type) {
+            format!("{} ({})", zone_type, zone)
+        } else {
+            zone_type
+        };
+        thermal_zones.insert(key, temp);
+    }
+
+    thermal_zones
+}
+
+fn collect_disks(mounts: &str) -> Vec<DiskStats> {
+    let mut seen = HashSet::new();
+    let mut disks = vec![];
+
+    for (device, mount_point, filesystem) in parse_mounts(mounts) {
+        // Bind mounts show up once per mount point; the first one wins
+        if !seen.insert(mount_point.clone()) {
+            continue;
+        }
+
+        match rustix::fs::statvfs(mount_point.as_str()) {
+            Ok(stat) => {
+                let total_bytes = stat.f_blocks * stat.f_frsize;
+                let free_bytes = stat.f_bfree * stat.f_frsize;
+                disks.push(DiskStats {
+                    mount_point,
+                    device,
+                    filesystem,
+                    total_bytes,
+                    available_bytes: stat.f_bavail * stat.f_frsize,
+                    used_bytes: total_bytes.saturating_sub(free_bytes),
+                });
+            }
+            Err(e) => debug!("Unable to stat {}: {}", mount_point, e),
+        }
+    }
+
+    disks
+}
+
+/// Returns `(device, mount point, filesystem)` for every mount backed by real storage.
+fn parse_mounts(mounts: &str) -> Vec<(String, String, String)> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let device = fields.next()?;
+            let mount_point = fields.next()?;
+            let filesystem = fields.next()?;
+
+            if PSEUDO_FILESYSTEMS.contains(&filesystem) {
+                return None;
+            }
+
+            // /proc/mounts escapes whitespace in paths as octal
+            let mount_point = mount_point
+                .replace("\\040", " ")
+                .replace("\\011", "\t")
+                .replace("\\134", "\\");
+
+            Some((device.to_string(), mount_point, filesystem.to_string()))
+        })
+        .collect()
+}
+
+fn parse_uptime(uptime: &str) -> Option<f64> {
+    uptime.split_whitespace().next()?.parse().ok()
+}
+
+fn parse_loadavg(loadavg: &str) -> Option<LoadAverage> {
+    let mut fields = loadavg.split_whitespace();
+    Some(LoadAverage {
+        one: fields.next()?.parse().ok()?,
+        five: fields.next()?.parse().ok()?,
+        fifteen: fields.next()?.parse().ok()?,
+    })
+}
+
+/// Looks up a `/proc/meminfo` entry, converted to bytes.
+fn meminfo_bytes(meminfo: &str, key: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name != key {
+            return None;
+        }
+
+        let mut fields = value.split_whitespace();
+        let value: u64 = fields.next()?.parse().ok()?;
+        match fields.next() {
+            Some("kB") => Some(value * 1024),
+            None => Some(value),
+            Some(_) => None,
+        }
+    })
+}
+
+fn parse_memory(meminfo: &str) -> Option<MemoryStats> {
+    let total_bytes = meminfo_bytes(meminfo, "MemTotal")?;
+    // Kernels older than 3.14 don't report MemAvailable
+    let available_bytes =
+        meminfo_bytes(meminfo, "MemAvailable").or_else(|| meminfo_bytes(meminfo, "MemFree"))?;
+
+    Some(MemoryStats {
+        total_bytes,
+        available_bytes,
+        used_bytes: total_bytes.saturating_sub(available_bytes),
+    })
+}
+
+fn parse_swap(meminfo: &str) -> Option<SwapStats> {
+    let total_bytes = meminfo_bytes(meminfo, "SwapTotal")?;
+    let free_bytes = meminfo_bytes(meminfo, "SwapFree")?;
+
+    Some(SwapStats {
+        total_bytes,
+        free_bytes,
+        used_bytes: total_bytes.saturating_sub(free_bytes),
+    })
+}
+
+fn parse_net_dev(net_dev: &str) -> Vec<NetworkInterfaceStats> {
+    net_dev
+        .lines()
+        // The first two lines are column headers
+        .skip(2)
+        .filter_map(|line| {
+            let (name, counters) = line.split_once(':')?;
+            let counters: Vec<u64> = counters
+                .split_whitespace()
+                .map(|counter| counter.parse().ok())
+                .collect::<Option<_>>()?;
+            if counters.len() < 16 {
+                return None;
+            }
+
+            Some(NetworkInterfaceStats {
+                name: name.trim().to_string(),
+                rx_bytes: counters[0],
+                rx_packets: counters[1],
+                rx_errors: counters[2],
+                rx_dropped: counters[3],
+                tx_bytes: counters[8],
+                tx_packets: counters[9],
+                tx_errors: counters[10],
+                tx_dropped: counters[11],
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpu_percent() {
+        // Half of the system's time over four CPUs is two full cores
+        assert_eq!(cpu_percent(500, 1000, 4), 200.0);
+        assert_eq!(cpu_percent(0, 1000, 4), 0.0);
+        // The first sample of a freshly started container has no previous reading
+        assert_eq!(cpu_percent(500, 0, 4), 0.0);
+    }
+
+    #[test]
+    fn test_parse_memory_and_swap() {
+        let meminfo = "MemTotal:        3881748 kB\n\
+                       MemFree:          204852 kB\n\
+                       MemAvailable:    2874604 kB\n\
+                       Buffers:          160596 kB\n\
+                       SwapTotal:        102396 kB\n\
+                       SwapFree:          51200 kB\n";
+
+        assert_eq!(
+            parse_memory(meminfo),
+            Some(MemoryStats {
+                total_bytes: 3881748 * 1024,
+                available_bytes: 2874604 * 1024,
+                used_bytes: (3881748 - 2874604) * 1024,
+            })
+        );
+        assert_eq!(
+            parse_swap(meminfo),
+            Some(SwapStats {
+                total_bytes: 102396 * 1024,
+                free_bytes: 51200 * 1024,
+                used_bytes: (102396 - 51200) * 1024,
+            })
+        );
+
+        let old_kernel = "MemTotal: 1000 kB\nMemFree: 400 kB\n";
+        assert_eq!(
+            parse_memory(old_kernel).map(|memory| memory.available_bytes),
+            Some(400 * 1024)
+        );
+        assert_eq!(parse_swap(old_kernel), None);
+    }
+
+    #[test]
+    fn test_parse_uptime_and_loadavg() {
+        assert_eq!(parse_uptime("350735.47 234388.90\n"), Some(350735.47));
+        assert_eq!(
+            parse_loadavg("0.52 0.58 0.59 1/389 12345\n"),
+            Some(LoadAverage {
+                one: 0.52,
+                five: 0.58,
+                fifteen: 0.59,
+            })
+        );
+        assert_eq!(parse_loadavg(""), None);
+    }
+
+    #[test]
+    fn test_parse_mounts() {
+        let mounts = "/dev/root / ext4 ro,relatime 0 0\n\
+                      proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0\n\
+                      tmpfs /run tmpfs rw,nosuid,nodev,mode=755 0 0\n\
+                      /dev/mmcblk0p1 /mnt/boot vfat rw,relatime 0 0\n\
+                      /dev/mmcblk0p6 /mnt/data\\040volume ext4 rw,relatime 0 0\n\
+                      overlay /var/lib/docker/overlay2/abc/merged overlay rw 0 0\n";
+
+        assert_eq!(
+            parse_mounts(mounts),
+            vec![
+                ("/dev/root".to_string(), "/".to_string(), "ext4".to_string()),
+                (
+                    "/dev/mmcblk0p1".to_string(),
+                    "/mnt/boot".to_string(),
+                    "vfat".to_string()
+                ),
+                (
+                    "/dev/mmcblk0p6".to_string(),
+                    "/mnt/data volume".to_string(),
+                    "ext4".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_net_dev() {
+        let net_dev = "Inter-|   Receive                                                |  Transmit\n \
+                       face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed\n    \
+                       lo: 1296 16 0 0 0 0 0 0 1296 16 0 0 0 0 0 0\n  \
+                       eth0: 98765 432 1 2 0 0 0 7 12345 210 3 4 0 0 0 0\n";
+
+        assert_eq!(
+            parse_net_dev(net_dev),
+            vec![
+                NetworkInterfaceStats {
+                    name: "lo".to_string(),
+                    rx_bytes: 1296,
+                    rx_packets: 16,
+                    tx_bytes: 1296,
+                    tx_packets: 16,
+                    ..Default::default()
+                },
+                NetworkInterfaceStats {
+                    name: "eth0".to_string(),
+                    rx_bytes: 98765,
+                    rx_packets: 432,
+                    rx_errors: 1,
+                    rx_dropped: 2,
+                    tx_bytes: 12345,
+                    tx_packets: 210,
+                    tx_errors: 3,
+                    tx_dropped: 4,
+                },
+            ]
+        );
+    }
+}
@@ -24,6 +24,13 @@ pub enum Relation {
     )]
     DeviceSchedule,
 
+    #[sea_orm(
+        has_many = "super::device_container_state::Entity",
+        from = "Column::Id",
+        to = "super::device_container_state::Column::DeviceId"
+    )]
+    DeviceContainerState,
+
     #[sea_orm(
         has_one = "super::waiting_room::Entity"
     )]
@@ -43,6 +50,12 @@ impl Related<super::device_schedule::Entity> for Entity {
     }
 }
 
+impl Related<super::device_container_state::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::DeviceContainerState.def()
+    }
+}
+
 impl Related<super::waiting_room::Entity> for Entity {
     fn to() -> RelationDef {
         Relation::WaitingRoom.def()
@@ -0,0 +1,51 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// The most recent state a device reported for one of its containers.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[sea_orm(table_name = "device_container_state")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false, default = "uuid_generate_v4()")]
+    pub id: uuid::Uuid,
+
+    #[sea_orm(column_type = "Uuid", indexed)]
+    pub device_id: uuid::Uuid,
+
+    #[sea_orm(column_type = "Text")]
+    pub task_id: String,
+
+    #[sea_orm(column_type = "Text")]
+    pub name: String,
+
+    #[sea_orm(column_type = "Text")]
+    pub status: String,
+
+    #[sea_orm(column_type = "Text")]
+    pub health: String,
+
+    #[sea_orm(column_type = "Text")]
+    pub error: String,
+
+    #[sea_orm(column_type = "Text")]
+    pub schedule_id: String,
+
+    pub reported_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::device::Entity",
+        from = "Column::DeviceId",
+        to = "super::device::Column::Id"
+    )]
+    Device,
+}
+
+impl Related<super::device::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Device.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -1,6 +1,7 @@
 pub mod schedule;
 pub mod device;
 pub mod device_schedule;
+pub mod device_container_state;
 pub mod waiting_room;
 pub mod organization;
 pub mod fleet;
@@ -1,6 +1,7 @@
 pub use sea_orm_migration::prelude::*;
 
 mod m20250329_235956_initial;
+mod m20261017_000000_device_container_state;
 
 pub struct Migrator;
 
@@ -9,6 +10,7 @@ impl MigratorTrait for Migrator {
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
         vec![
             Box::new(m20250329_235956_initial::Migration),
+            Box::new(m20261017_000000_device_container_state::Migration),
         ]
     }
 }
@@ -0,0 +1,28 @@
+use sea_orm_migration::{prelude::*, sea_orm::Schema};
+
+use entity::device_container_state::Entity as DeviceContainerState;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let builder = manager.get_database_backend();
+        let schema = Schema::new(builder);
+
+        manager
+            .create_table(schema.create_table_from_entity(DeviceContainerState))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(DeviceContainerState).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
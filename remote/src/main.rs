@@ -6,7 +6,7 @@ use pando_core::grpc_remote::{
 };
 use sea_orm::ActiveValue::Set;
 use sea_orm::ColumnTrait;
-use sea_orm::{ActiveModelTrait, ConnectOptions, QueryFilter};
+use sea_orm::{ActiveModelTrait, ConnectOptions, QueryFilter, QueryOrder, TransactionTrait};
 use sea_orm::{Database, DatabaseConnection, EntityTrait};
 use std::time::Duration;
 use std::{fs::File, io::ErrorKind, path::Path};
@@ -18,10 +18,11 @@ use tracing_subscriber::FmtSubscriber;
 use uuid::Uuid;
 
 use entity::device::Entity as Device;
+use entity::device_schedule::Entity as DeviceScheduleEntity;
 use entity::schedule::ActiveModel as ScheduleModel;
 use entity::schedule::Entity as ScheduleEntity;
 
-use entity::{device, waiting_room};
+use entity::{device, device_container_state, device_schedule, waiting_room};
 
 #[derive(Debug)]
 pub(crate) struct PandoRemoteServer {
@@ -65,13 +66,34 @@ impl pando_core::grpc_remote::remote_service_server::RemoteService for PandoRemo
         request: tonic::Request<pando_core::grpc_remote::PublishScheduleRequest>,
     ) -> Result<tonic::Response<pando_core::grpc_remote::PublishScheduleResponse>, tonic::Status>
     {
-        let schedule_body = request.into_inner().schedule.ok_or_else(|| {
+        let request = request.into_inner();
+        let schedule_body = request.schedule.ok_or_else(|| {
             tracing::error!("Schedule is required");
             tonic::Status::invalid_argument("Schedule is required")
         })?;
 
         debug!("Received PublishScheduleRequest {:?}", schedule_body);
 
+        let fleet_id = match request.fleet_id.trim() {
+            "" => None,
+            fleet_id => Some(Uuid::parse_str(fleet_id).map_err(|e| {
+                tracing::error!("Invalid fleet id {}: {}", fleet_id, e);
+                tonic::Status::invalid_argument("Invalid fleet id")
+            })?),
+        };
+        let mut device_ids = Vec::with_capacity(request.device_ids.len());
+        for device_id in &request.device_ids {
+            device_ids.push(Uuid::parse_str(device_id.trim()).map_err(|e| {
+                tracing::error!("Invalid device id {}: {}", device_id, e);
+                tonic::Status::invalid_argument("Invalid device id")
+            })?);
+        }
+        if fleet_id.is_none() && device_ids.is_empty() {
+            return Err(tonic::Status::invalid_argument(
+                "A device or fleet to publish to is required",
+            ));
+        }
+
         let schedule_body_json = serde_json::to_string(&schedule_body).map_err(|e| {
             tracing::error!("Failed to serialize schedule body: {}", e);
             tonic::Status::internal("Failed to serialize schedule body")
@@ -88,16 +110,30 @@ impl pando_core::grpc_remote::remote_service_server::RemoteService for PandoRemo
             tonic::Status::internal("Failed to insert schedule record")
         })?;
 
-        let all_devices = Device::find().all(&self.connection).await.map_err(|e| {
-            tracing::error!("Failed to fetch devices: {}", e);
-            tonic::Status::internal("Failed to fetch devices")
-        })?;
+        // Devices don't know their fleet, so a fleet is published to device by device
+        let mut targets = device_ids;
+        if let Some(fleet_id) = fleet_id {
+            let fleet_devices = Device::find()
+                .filter(device::Column::FleetId.eq(fleet_id))
+                .all(&self.connection)
+                .await
+                .map_err(|e| {
+                    tracing::error!("Failed to fetch devices: {}", e);
+                    tonic::Status::internal("Failed to fetch devices")
+                })?;
+            targets.extend(fleet_devices.into_iter().map(|device| device.id));
+        }
+        targets.sort();
+        targets.dedup();
 
-        for device in all_devices {
-            debug!("Publishing schedule to device {:?}", device.id);
+        for device_id in targets {
+            debug!("Publishing schedule to device {:?}", device_id);
+
+            // Recorded so devices that miss the message pick the schedule up via GetSchedule
+            self.assign_schedule(device_id, schedule_record.id).await?;
 
             self.nats_client
-                .emit_schedule(device.id.to_string(), &schedule_body)
+                .emit_schedule(device_id.to_string(), &schedule_body)
                 .await
                 .map_err(|e| {
                     tracing::error!("Failed to publish schedule: {}", e);
@@ -192,7 +228,59 @@ impl pando_core::grpc_remote::remote_service_server::RemoteService for PandoRemo
     }
 }
 
+fn schedule_from_record(
+    record: entity::schedule::Model,
+) -> Result<pando_core::grpc_remote::Schedule, serde_json::Error> {
+    // publish_schedule stores the body as a JSON-encoded string
+    let mut schedule: pando_core::grpc_remote::Schedule = match record.body {
+        serde_json::Value::String(body) => serde_json::from_str(&body)?,
+        body => serde_json::from_value(body)?,
+    };
+
+    // Agents won't run a schedule without an id
+    if schedule.id.is_empty() {
+        schedule.id = record.id.to_string();
+    }
+
+    Ok(schedule)
+}
+
 impl PandoRemoteServer {
+    /// Points a device at a schedule, replacing whatever it was assigned before.
+    async fn assign_schedule(&self, device_id: Uuid, schedule_id: Uuid) -> Result<(), Status> {
+        let existing = DeviceScheduleEntity::find()
+            .filter(device_schedule::Column::DeviceId.eq(device_id))
+            .order_by_desc(device_schedule::Column::Id)
+            .one(&self.connection)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to fetch device schedule record: {}", e);
+                tonic::Status::internal("Failed to assign schedule")
+            })?;
+
+        let result = match existing {
+            Some(existing) => device_schedule::ActiveModel {
+                schedule_id: Set(schedule_id),
+                ..existing.into()
+            }
+            .update(&self.connection)
+            .await
+            .map(|_| ()),
+            None => DeviceScheduleEntity::insert(device_schedule::ActiveModel {
+                id: Set(Uuid::now_v7()),
+                device_id: Set(device_id),
+                schedule_id: Set(schedule_id),
+            })
+            .exec(&self.connection)
+            .await
+            .map(|_| ()),
+        };
+        result.map_err(|e| {
+            tracing::error!("Failed to save device schedule record: {}", e);
+            tonic::Status::internal("Failed to assign schedule")
+        })
+    }
+
     async fn get_or_create_waiting_room_record(
         &self,
         temporary_device_identifier: String,
@@ -247,7 +335,6 @@ impl PandoRemoteServer {
 
 #[tonic::async_trait]
 impl pando_core::grpc_remote::device_service_server::DeviceService for PandoRemoteServer {
-
     async fn start_anonymous_device_registration(
         &self,
         request: tonic::Request<pando_core::grpc_remote::StartAnonymousDeviceRegistrationRequest>,
@@ -396,17 +483,138 @@ impl pando_core::grpc_remote::device_service_server::DeviceService for PandoRemo
 
     async fn get_schedule(
         &self,
-        _request: tonic::Request<pando_core::grpc_remote::GetScheduleRequest>,
+        request: tonic::Request<pando_core::grpc_remote::GetScheduleRequest>,
     ) -> Result<tonic::Response<pando_core::grpc_remote::GetScheduleResponse>, tonic::Status> {
-        todo!()
+        let request = request.into_inner();
+        let device_id = Uuid::parse_str(request.device_id.trim()).map_err(|e| {
+            tracing::error!("Invalid device id {}: {}", request.device_id, e);
+            tonic::Status::invalid_argument("Invalid device id")
+        })?;
+        debug!("Received GetScheduleRequest for device {}", device_id);
+
+        // Assignment ids are v7 UUIDs, so the highest one is the latest assignment
+        let assignment = DeviceScheduleEntity::find()
+            .filter(device_schedule::Column::DeviceId.eq(device_id))
+            .order_by_desc(device_schedule::Column::Id)
+            .one(&self.connection)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to fetch device schedule: {}", e);
+                tonic::Status::internal("Failed to fetch device schedule")
+            })?;
+
+        let Some(assignment) = assignment else {
+            return Ok(tonic::Response::new(
+                pando_core::grpc_remote::GetScheduleResponse { schedule: None },
+            ));
+        };
+
+        let schedule_record = ScheduleEntity::find_by_id(assignment.schedule_id)
+            .one(&self.connection)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to fetch schedule: {}", e);
+                tonic::Status::internal("Failed to fetch schedule")
+            })?
+            .ok_or_else(|| {
+                tracing::error!(
+                    "Device schedule points at missing schedule {}",
+                    assignment.schedule_id
+                );
+                tonic::Status::internal("Assigned schedule not found")
+            })?;
+
+        let mut schedule = schedule_from_record(schedule_record).map_err(|e| {
+            tracing::error!(
+                "Failed to deserialize schedule {}: {}",
+                assignment.schedule_id,
+                e
+            );
+            tonic::Status::internal("Failed to deserialize schedule")
+        })?;
+        // Callers aren't authenticated, so registry passwords stay out of the response. Devices
+        // pull with their own credentials instead.
+        schedule.registry_credentials.clear();
+
+        Ok(tonic::Response::new(
+            pando_core::grpc_remote::GetScheduleResponse {
+                schedule: Some(schedule),
+            },
+        ))
     }
 
     async fn report_schedule_state(
         &self,
-        _request: tonic::Request<pando_core::grpc_remote::ReportScheduleStateRequest>,
+        request: tonic::Request<pando_core::grpc_remote::ReportScheduleStateRequest>,
     ) -> Result<tonic::Response<pando_core::grpc_remote::ReportScheduleStateResponse>, tonic::Status>
     {
-        todo!()
+        let report = request.into_inner();
+        debug!(
+            "Received ReportScheduleStateRequest for device {} ({} containers)",
+            report.device_id,
+            report.container_states.len()
+        );
+
+        let device_id = Uuid::parse_str(report.device_id.trim()).map_err(|e| {
+            tracing::error!("Invalid device id {}: {}", report.device_id, e);
+            tonic::Status::invalid_argument("Invalid device id")
+        })?;
+
+        Device::find_by_id(device_id)
+            .one(&self.connection)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to fetch device: {}", e);
+                tonic::Status::internal("Failed to fetch device")
+            })?
+            .ok_or_else(|| tonic::Status::not_found("Device not found"))?;
+
+        // Each report is a complete picture, so it replaces whatever the device reported before
+        let reported_at = chrono::Utc::now().naive_utc();
+        let transaction = self.connection.begin().await.map_err(|e| {
+            tracing::error!("Failed to start transaction: {}", e);
+            tonic::Status::internal("Failed to record container states")
+        })?;
+
+        device_container_state::Entity::delete_many()
+            .filter(device_container_state::Column::DeviceId.eq(device_id))
+            .exec(&transaction)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to clear container states: {}", e);
+                tonic::Status::internal("Failed to record container states")
+            })?;
+
+        if !report.container_states.is_empty() {
+            device_container_state::Entity::insert_many(report.container_states.into_iter().map(
+                |state| device_container_state::ActiveModel {
+                    id: Set(Uuid::now_v7()),
+                    device_id: Set(device_id),
+                    task_id: Set(state.id),
+                    name: Set(state.name),
+                    status: Set(state.status),
+                    health: Set(state.health),
+                    error: Set(state.error),
+                    schedule_id: Set(state.schedule_id),
+                    reported_at: Set(reported_at),
+                },
+            ))
+            .exec(&transaction)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to insert container states: {}", e);
+                tonic::Status::internal("Failed to record container states")
+            })?;
+        }
+
+        transaction.commit().await.map_err(|e| {
+            tracing::error!("Failed to commit container states: {}", e);
+            tonic::Status::internal("Failed to record container states")
+        })?;
+
+        Ok(tonic::Response::new(
+            pando_core::grpc_remote::ReportScheduleStateResponse {},
+        ))
     }
 }
 